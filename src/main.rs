@@ -1,53 +1,286 @@
 mod pinoq;
 
-use clap::Parser;
-use pinoq::config::Config;
+use anyhow::Context;
+use clap::{ArgGroup, Args, Parser, Subcommand};
+use pinoq::{
+    config::{combine_keyfile, Config},
+    prompt, Allocation, Kdf, KdfAlgorithm, MkfsOptions, PinoqError, Secret, Size, ASPECT_SLOTS,
+};
 
+/// A deniable encrypted filesystem
 #[derive(Debug, Parser)]
-#[command(version, arg_required_else_help = true)]
-struct Args {
-    /// Mount a volume based on specified config
-    #[clap(long("mount"))]
-    config_path: Option<String>,
-    /// Create a pinoq volume with the specified size
-    #[clap(
-        long("mkfs"),
-        num_args = 4,
-        value_names = ["ASPECTS", "BLOCKS", "PATH", "PASSWORD"],
-    )]
-    mkfs: Vec<String>,
+#[command(
+    version,
+    arg_required_else_help = true,
+    after_help = "Exit codes: 0 on success, 1 when the command fails, 2 on bad usage, \
+                  3 when the aspect can't be unlocked, 4 when the disk isn't a usable volume \
+                  or is corrupted"
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Create a pinoq volume, the password of each aspect is asked
+    Mkfs(MkfsArgs),
+    /// Mount a volume based on the specified config
+    Mount {
+        #[arg(value_name = "CONFIG_PATH")]
+        config: String,
+    },
+    /// Unmount a mounted volume
+    Umount { mountpoint: String },
     /// Inspect information from a pinoq disk
-    #[clap(long("inspect"), value_names = ["PATH"])]
-    inspect_path: Option<String>,
-}
-
-fn parse_args() -> anyhow::Result<()> {
-    let args = Args::parse();
-
-    if let Some(path) = args.config_path {
-        let config = match std::fs::read_to_string(path) {
-            Ok(c) => Config::new(&c),
-            _ => panic!("Couldn't find the file"),
-        }?;
-        pinoq::mount(config);
-    } else if args.mkfs.len() > 0 {
-        let aspects = args.mkfs[0].parse::<u32>()?;
-        let blocks = args.mkfs[1].parse::<u32>()?;
-        pinoq::mkfs(aspects, blocks, &args.mkfs[2], &args.mkfs[3])?;
-    } else if let Some(path) = args.inspect_path {
-        pinoq::inspect(&path)?;
+    Inspect { path: String },
+    /// Check the aspect of a config, the problems found are printed as JSON lines
+    /// (the volume must not be mounted)
+    Fsck {
+        #[arg(value_name = "CONFIG_PATH")]
+        config: String,
+        /// Fix what's found: orphan blocks are freed, broken chains truncated
+        /// and a missing root recreated
+        #[arg(long)]
+        repair: bool,
+    },
+    /// List a directory of the volume of a config (the volume must not be mounted)
+    Ls {
+        #[arg(value_name = "CONFIG_PATH")]
+        config: String,
+        #[arg(default_value = "/")]
+        path: String,
+    },
+    /// Print a file of the volume of a config (the volume must not be mounted)
+    Cat {
+        #[arg(value_name = "CONFIG_PATH")]
+        config: String,
+        path: String,
+    },
+    /// Store stdin in a file of the volume of a config (the volume must not be mounted)
+    ///
+    /// The passwords must be set in the config or read from elsewhere than stdin
+    Put {
+        #[arg(value_name = "CONFIG_PATH")]
+        config: String,
+        path: String,
+    },
+    /// Remove a file of the volume of a config (the volume must not be mounted)
+    Rm {
+        #[arg(value_name = "CONFIG_PATH")]
+        config: String,
+        path: String,
+        /// Remove directories along with their content
+        #[arg(short, long)]
+        recursive: bool,
+    },
+    /// Copy files between the host and the volume of a config, the paths in the volume
+    /// start with `:` (the volume must not be mounted)
+    Cp {
+        #[arg(value_name = "CONFIG_PATH")]
+        config: String,
+        src: String,
+        dst: String,
+        /// Copy directories along with their content
+        #[arg(short, long)]
+        recursive: bool,
+    },
+    /// Change the password of the aspect of a config, the new one is asked (the volume must not be mounted)
+    Passwd {
+        #[arg(value_name = "CONFIG_PATH")]
+        config: String,
+        /// Keyfile combined with the new password
+        #[arg(long, value_name = "PATH")]
+        keyfile: Option<String>,
+    },
+    /// Replace the key encrypting the blocks of the aspect of a config (the volume must not be mounted)
+    Rekey {
+        #[arg(value_name = "CONFIG_PATH")]
+        config: String,
+    },
+}
+
+#[derive(Debug, Args)]
+#[command(group(ArgGroup::new("volume_size").required(true).args(["size", "blocks", "existing"])))]
+struct MkfsArgs {
+    path: String,
+    /// Number of aspects, each one with its own password
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..=ASPECT_SLOTS as i64))]
+    aspects: u32,
+    /// Size of the volume, e.g. 512M or 2G
+    #[arg(long, value_parser = parse_size)]
+    size: Option<u64>,
+    /// Number of blocks instead of a size, a multiple of 8
+    #[arg(long, conflicts_with = "size")]
+    blocks: Option<u32>,
+    /// Overwrite the existing file or device at PATH, keeping its size
+    #[arg(long, conflicts_with_all = ["size", "blocks"])]
+    existing: bool,
+    /// Key derivation function protecting the aspects
+    #[arg(long, value_parser = ["pbkdf2", "scrypt"], default_value = "scrypt")]
+    kdf: String,
+    /// Cost of the key derivation function (iterations for pbkdf2, log2(N) for scrypt)
+    #[arg(long, value_name = "COST")]
+    kdf_cost: Option<u32>,
+    /// Don't fill the volume with random data (faster, but reveals the amount of stored data),
+    /// not allowed with --existing since the old content would be kept
+    #[arg(long, conflicts_with = "existing")]
+    fast: bool,
+    /// Don't write a superblock, the volume is indistinguishable from random data
+    /// (the same KDF parameters have to be set in the config to mount it)
+    #[arg(long)]
+    headerless: bool,
+    /// How the blocks are allocated
+    #[arg(long, value_parser = ["random", "first-fit"], default_value = "random")]
+    allocation: String,
+    /// Keyfile combined with the password, one per aspect in order
+    #[arg(long, value_name = "PATH")]
+    keyfile: Vec<String>,
+}
+
+fn run(cli: Cli) -> anyhow::Result<()> {
+    match cli.command {
+        Command::Mkfs(args) => mkfs(args),
+        Command::Mount { config } => pinoq::mount(load_config(&config)?),
+        Command::Umount { mountpoint } => pinoq::umount(&mountpoint),
+        Command::Inspect { path } => pinoq::inspect(&path),
+        Command::Fsck { config, repair } => pinoq::fsck(load_config(&config)?, repair),
+        Command::Ls { config, path } => pinoq::ls(load_config(&config)?, &path),
+        Command::Cat { config, path } => pinoq::cat(load_config(&config)?, &path),
+        Command::Put { config, path } => pinoq::put(load_config(&config)?, &path),
+        Command::Rm {
+            config,
+            path,
+            recursive,
+        } => pinoq::rm(load_config(&config)?, &path, recursive),
+        Command::Cp {
+            config,
+            src,
+            dst,
+            recursive,
+        } => pinoq::cp(load_config(&config)?, &src, &dst, recursive),
+        Command::Passwd { config, keyfile } => {
+            let config = load_config(&config)?;
+            pinoq::passwd(&config, || new_secret("New password: ", keyfile.as_deref()))
+        }
+        Command::Rekey { config } => pinoq::rekey(&load_config(&config)?),
     }
+}
 
+fn mkfs(args: MkfsArgs) -> anyhow::Result<()> {
+    if args.keyfile.len() > args.aspects as usize {
+        anyhow::bail!("expected at most one keyfile per aspect");
+    }
+    let size = match (args.blocks, args.size, args.existing) {
+        (Some(blocks), _, _) => Size::Blocks(blocks),
+        (None, Some(size), _) => Size::Bytes(size),
+        (None, None, true) => Size::Existing,
+        (None, None, false) => anyhow::bail!("expected --size, --blocks or --existing"),
+    };
+    let algorithm = match args.kdf.as_str() {
+        "pbkdf2" => KdfAlgorithm::Pbkdf2,
+        _ => KdfAlgorithm::Scrypt,
+    };
+    let kdf = Kdf::new(algorithm, args.kdf_cost);
+    kdf.validate()
+        .with_context(|| format!("invalid cost for {}", args.kdf))?;
+
+    let secrets = (0..args.aspects as usize)
+        .map(|i| {
+            let prompt = format!("Password for aspect {}: ", i);
+            new_secret(&prompt, args.keyfile.get(i).map(String::as_str))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let options = MkfsOptions {
+        kdf,
+        fast: args.fast,
+        headerless: args.headerless,
+        allocation: match args.allocation.as_str() {
+            "first-fit" => Allocation::FirstFit,
+            _ => Allocation::Random,
+        },
+    };
+    let geometry = pinoq::mkfs(&secrets, size, &args.path, &options)?;
+    println!("{}", geometry);
     Ok(())
 }
 
+fn load_config(path: &str) -> anyhow::Result<Config> {
+    Config::from_file(path).with_context(|| format!("Couldn't read the config {}", path))
+}
+
+/// asks for a new password, which may only be empty along with a keyfile
+fn new_secret(prompt: &str, keyfile: Option<&str>) -> anyhow::Result<Secret> {
+    let password = prompt::read_new_password(prompt)?;
+    if password.is_empty() && keyfile.is_none() {
+        anyhow::bail!("empty password");
+    }
+    Ok(combine_keyfile(&password, keyfile)?)
+}
+
+/// sizes in bytes, with an optional binary suffix (K, M, G or T)
+fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let (digits, shift) = match s.char_indices().last() {
+        Some((i, 'K' | 'k')) => (&s[..i], 10),
+        Some((i, 'M' | 'm')) => (&s[..i], 20),
+        Some((i, 'G' | 'g')) => (&s[..i], 30),
+        Some((i, 'T' | 't')) => (&s[..i], 40),
+        _ => (s, 0),
+    };
+    let n = digits
+        .parse::<u64>()
+        .map_err(|_| format!("invalid size `{}`", s))?;
+    n.checked_mul(1 << shift)
+        .ok_or_else(|| format!("size `{}` is too large", s))
+}
+
+fn exit_code(e: &anyhow::Error) -> i32 {
+    match e.downcast_ref::<PinoqError>() {
+        Some(PinoqError::WrongPassword) => 3,
+        // a wrong password is reported as such, a block failing authentication is tampered with
+        Some(
+            PinoqError::Authentication
+            | PinoqError::BadMagic
+            | PinoqError::Truncated
+            | PinoqError::UnsupportedVersion,
+        ) => 4,
+        _ => 1,
+    }
+}
+
 fn main() {
     pretty_env_logger::formatted_builder()
         .parse_filters("DEBUG")
         .init();
 
-    if let Err(e) = parse_args() {
-        eprintln!("pionq: {:?}", e);
-        std::process::exit(1);
+    if let Err(e) = run(Cli::parse()) {
+        eprintln!("pinoq: {:#}", e);
+        std::process::exit(exit_code(&e));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("4096"), Ok(4096));
+        assert_eq!(parse_size("512M"), Ok(512 << 20));
+        assert_eq!(parse_size("2g"), Ok(2 << 30));
+        assert!(parse_size("").is_err());
+        assert!(parse_size("M").is_err());
+        assert!(parse_size("1.5G").is_err());
+        assert!(parse_size("16777216T").is_err());
+    }
+
+    #[test]
+    fn test_exit_code() {
+        let code = |e: PinoqError| exit_code(&e.into());
+        assert_eq!(code(PinoqError::WrongPassword), 3);
+        assert_eq!(code(PinoqError::Authentication), 4);
+        assert_eq!(code(PinoqError::BadMagic), 4);
+        assert_eq!(code(PinoqError::NoEntry), 1);
     }
 }
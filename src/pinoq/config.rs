@@ -1,24 +1,311 @@
+use std::fs::File;
+use std::io::Read;
+use std::mem::ManuallyDrop;
+use std::os::fd::FromRawFd;
+use std::path::PathBuf;
+
+use crate::pinoq::encryption::{Kdf, Secret};
 use crate::pinoq::error::{PinoqError, Result};
+use crate::pinoq::journal::Journal;
+use crate::pinoq::prompt;
 use serde::Deserialize;
 
+// the most read from a descriptor or a file that isn't a regular one
+const MAX_SECRET_LEN: usize = 64 << 10;
+
 #[derive(Deserialize)]
 pub struct Config {
     pub disk: String,
     pub mount: String,
     pub current: Current,
+    /// never store real timestamps, everything stays at the epoch
+    #[serde(default)]
+    pub hide_timestamps: bool,
+    /// the volume has no superblock, so `kdf` must match the one used by mkfs
+    #[serde(default)]
+    pub headerless: bool,
+    #[serde(default)]
+    pub kdf: Kdf,
+    /// other aspects whose blocks must never be allocated, they're only read
+    #[serde(default)]
+    pub protect: Vec<Current>,
+    /// the directory keeping the journals of passwd and rekey, next to the disk by default
+    /// it has to survive a reboot, which the one of a block device in /dev doesn't
+    #[serde(default)]
+    pub journal: Option<String>,
 }
 
 #[derive(Deserialize)]
 pub struct Current {
     pub aspect: u32,
-    pub password: String,
+    /// better left out, so the config never holds it
+    /// it's then read from `password_from`, or asked on the terminal
+    #[serde(default)]
+    pub password: Option<String>,
+    #[serde(default)]
+    pub password_from: Option<SecretSource>,
+    /// combined with the password, if any
+    #[serde(default)]
+    pub keyfile: Option<String>,
+}
+
+/// where to read a password from, a trailing newline is dropped
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SecretSource {
+    /// asked on the terminal, or read from a pipe
+    Stdin,
+    /// an inherited descriptor, read to the end but left open
+    Fd(i32),
+    Env(String),
+    File(String),
+    /// a systemd credential, see `LoadCredential=`
+    Credential(String),
 }
 
 impl Config {
     pub fn new(config: &str) -> Result<Self> {
-        match toml::from_str(config) {
-            Ok(c) => Ok(c),
-            Err(_) => Err(PinoqError::InvalidConfig),
+        let config: Self = toml::from_str(config).map_err(|_| PinoqError::InvalidConfig)?;
+        config.kdf.validate()?;
+        Ok(config)
+    }
+
+    /// the content of the file is zeroed once parsed, it may hold passwords
+    pub fn from_file(path: &str) -> Result<Self> {
+        let content = read_file(path)?;
+        Self::new(std::str::from_utf8(&content).map_err(|_| PinoqError::InvalidConfig)?)
+    }
+
+    pub fn journal_dir(&self) -> PathBuf {
+        match &self.journal {
+            Some(dir) => PathBuf::from(dir),
+            None => Journal::default_dir(&self.disk),
+        }
+    }
+}
+
+impl Current {
+    /// the input of the KDF unlocking the aspect
+    /// a keyfile alone is enough, otherwise the password is asked when missing
+    pub fn secret(&self) -> Result<Secret> {
+        let password = match (&self.password_from, &self.password) {
+            (Some(source), _) => source.read(self.aspect)?,
+            (None, Some(password)) => Secret::new(password.as_bytes().to_vec()),
+            (None, None) if self.keyfile.is_some() => Secret::new(vec![]),
+            (None, None) => SecretSource::Stdin.read(self.aspect)?,
+        };
+        combine_keyfile(&password, self.keyfile.as_deref())
+    }
+
+    /// whether `secret` reads the password from stdin
+    pub fn reads_stdin(&self) -> bool {
+        match (&self.password_from, &self.password) {
+            (Some(source), _) => matches!(source, SecretSource::Stdin | SecretSource::Fd(0)),
+            (None, Some(_)) => false,
+            (None, None) => self.keyfile.is_none(),
+        }
+    }
+}
+
+impl Drop for Current {
+    fn drop(&mut self) {
+        if let Some(password) = self.password.take() {
+            drop(Secret::from(password));
+        }
+    }
+}
+
+impl SecretSource {
+    /// `aspect` is only used to prompt for the password
+    pub fn read(&self, aspect: u32) -> Result<Secret> {
+        let mut secret = match self {
+            Self::Stdin => {
+                let prompt = format!("Password for aspect {}: ", aspect);
+                return prompt::read_password(&prompt);
+            }
+            Self::Fd(fd) => {
+                // only a descriptor that's actually open is wrapped
+                if *fd < 0 || unsafe { libc::fcntl(*fd, libc::F_GETFD) } == -1 {
+                    return Err(PinoqError::InvalidConfig);
+                }
+                // the descriptor still belongs to whoever passed it, it's never closed here
+                let file = ManuallyDrop::new(unsafe { File::from_raw_fd(*fd) });
+                read_secret(&*file, MAX_SECRET_LEN)?
+            }
+            Self::Env(name) => {
+                Secret::from(std::env::var(name).map_err(|_| PinoqError::InvalidConfig)?)
+            }
+            Self::File(path) => read_file(path)?,
+            Self::Credential(name) => {
+                let dir = std::env::var("CREDENTIALS_DIRECTORY")
+                    .map_err(|_| PinoqError::InvalidConfig)?;
+                read_file(&format!("{}/{}", dir, name))?
+            }
+        };
+
+        if secret.ends_with(b"\n") {
+            let mut len = secret.len() - 1;
+            if secret[..len].ends_with(b"\r") {
+                len -= 1;
+            }
+            secret.truncate(len);
         }
+        Ok(secret)
+    }
+}
+
+/// reads a file that may hold secrets, sized up front when it's a regular one
+fn read_file(path: &str) -> Result<Secret> {
+    let file = File::open(path)?;
+    let metadata = file.metadata()?;
+    let cap = match metadata.is_file() {
+        true => metadata.len() as usize,
+        false => MAX_SECRET_LEN,
+    };
+    read_secret(file, cap)
+}
+
+/// reads at most `cap` bytes, into a buffer that's never reallocated
+/// so no copy of the secret is left behind
+fn read_secret<R: Read>(mut r: R, cap: usize) -> Result<Secret> {
+    // one more byte tells whether there was more to read
+    let mut secret = Secret::new(vec![0; cap + 1]);
+    let mut len = 0;
+    loop {
+        match r.read(&mut secret[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        }
+        if len > cap {
+            return Err(PinoqError::InvalidConfig);
+        }
+    }
+    secret.truncate(len);
+    Ok(secret)
+}
+
+/// appends the digest of the keyfile to the password, a keyfile can be any file
+pub fn combine_keyfile(password: &[u8], keyfile: Option<&str>) -> Result<Secret> {
+    // never reallocated, so no copy is left behind
+    let mut secret = Vec::with_capacity(password.len() + 32);
+    secret.extend_from_slice(password);
+    if let Some(path) = keyfile {
+        let content = Secret::new(std::fs::read(path)?);
+        secret.extend_from_slice(&openssl::sha::sha256(&content));
+    }
+    Ok(Secret::new(secret))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::fd::AsRawFd;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_secret() {
+        let dir = tempdir().unwrap();
+        let password_file = dir.path().join("password");
+        std::fs::write(&password_file, "from a file\n").unwrap();
+        let keyfile = dir.path().join("keyfile");
+        std::fs::write(&keyfile, [1, 2, 3]).unwrap();
+
+        let config = format!(
+            r#"
+            disk = "volume.pnoq"
+            mount = "/tmp/pinoq"
+
+            [current]
+            aspect = 0
+            password_from = {{ file = "{}" }}
+
+            [[protect]]
+            aspect = 1
+            password_from = {{ env = "PINOQ_TEST_PASSWORD" }}
+            keyfile = "{}"
+
+            [[protect]]
+            aspect = 2
+            keyfile = "{}"
+            "#,
+            password_file.display(),
+            keyfile.display(),
+            keyfile.display(),
+        );
+        let config = Config::new(&config).unwrap();
+        assert_eq!(&config.current.secret().unwrap()[..], b"from a file");
+
+        std::env::set_var("PINOQ_TEST_PASSWORD", "from the env");
+        let secret = config.protect[0].secret().unwrap();
+        assert_eq!(&secret[..12], b"from the env");
+        // the keyfile only takes part through its digest
+        assert_eq!(secret.len(), 12 + 32);
+        assert_eq!(&secret[12..], &config.protect[1].secret().unwrap()[..]);
+
+        assert!(!config.current.reads_stdin());
+        assert!(!config.protect[1].reads_stdin());
+
+        // a missing environment variable
+        std::env::remove_var("PINOQ_TEST_PASSWORD");
+        let result = config.protect[0].secret();
+        assert!(matches!(result, Err(PinoqError::InvalidConfig)));
+    }
+
+    #[test]
+    fn test_read_secret() {
+        let secret = read_secret(&b"password"[..], 8).unwrap();
+        assert_eq!(&secret[..], b"password");
+        let result = read_secret(&b"password"[..], 7);
+        assert!(matches!(result, Err(PinoqError::InvalidConfig)));
+    }
+
+    #[test]
+    fn test_secret_fd() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("password");
+        std::fs::write(&path, "from a descriptor\n").unwrap();
+        let file = File::open(&path).unwrap();
+        let fd = file.as_raw_fd();
+
+        let source = SecretSource::Fd(fd);
+        assert_eq!(&source.read(0).unwrap()[..], b"from a descriptor");
+        // read to the end, but not closed
+        assert_eq!(&source.read(0).unwrap()[..], b"");
+        assert_ne!(unsafe { libc::fcntl(fd, libc::F_GETFD) }, -1);
+
+        // not a descriptor, or not an open one
+        for fd in [-1, i32::MAX] {
+            let result = SecretSource::Fd(fd).read(0);
+            assert!(matches!(result, Err(PinoqError::InvalidConfig)));
+        }
+
+        // stdin by another name
+        let current = Current {
+            aspect: 0,
+            password: None,
+            password_from: Some(SecretSource::Fd(0)),
+            keyfile: None,
+        };
+        assert!(current.reads_stdin());
+    }
+
+    #[test]
+    fn test_kdf_parameters() {
+        let config = r#"
+            disk = "volume.pnoq"
+            mount = "/mnt"
+            [current]
+            aspect = 0
+            [kdf]
+            algorithm = "Scrypt"
+            cost = COST
+            block_size = 8
+            parallelism = 1
+        "#;
+        assert!(Config::new(&config.replace("COST", "14")).is_ok());
+        let result = Config::new(&config.replace("COST", "64"));
+        assert!(matches!(result, Err(PinoqError::InvalidArgument)));
     }
 }
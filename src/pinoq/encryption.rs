@@ -1,58 +1,279 @@
-use openssl::symm::{Cipher, Crypter, Mode};
+use crate::pinoq::error::{PinoqError, Result};
+
+use openssl::hash::MessageDigest;
+use openssl::pkcs5::{pbkdf2_hmac, scrypt};
+use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
 use serde::{Deserialize, Serialize};
 
-pub(crate) const IV_LEN: usize = 16;
+pub(crate) const IV_LEN: usize = 12;
+pub(crate) const TAG_LEN: usize = 16;
 pub(crate) const KEY_LEN: usize = 32;
+pub(crate) const SALT_LEN: usize = 16;
 
-#[derive(Debug, Default, Serialize, Deserialize, Clone)]
-pub struct Key(pub [u8; KEY_LEN]);
+pub const PBKDF2_ITERATIONS: u32 = 600_000;
+pub const SCRYPT_LOG_N: u32 = 15;
+
+/// kept on the heap so moving it around leaves no copies behind
+/// it's locked in memory and zeroed once dropped
+pub struct Key(pub Box<[u8; KEY_LEN]>);
+
+impl Key {
+    pub fn from_slice(bytes: &[u8]) -> Self {
+        let mut key = Self::default();
+        key.0.copy_from_slice(bytes);
+        key
+    }
+}
+
+impl Default for Key {
+    fn default() -> Self {
+        let key = Box::new([0; KEY_LEN]);
+        mlock(&key[..]);
+        Self(key)
+    }
+}
+
+impl Clone for Key {
+    fn clone(&self) -> Self {
+        Self::from_slice(&self.0[..])
+    }
+}
+
+impl Drop for Key {
+    fn drop(&mut self) {
+        zeroize(&mut self.0[..]);
+    }
+}
+
+impl std::fmt::Debug for Key {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Key(..)")
+    }
+}
+
+/// a password or anything else the keys are derived from
+/// it's locked in memory and zeroed once dropped
+pub struct Secret(Vec<u8>);
+
+impl Secret {
+    pub fn new(bytes: Vec<u8>) -> Self {
+        mlock(&bytes);
+        Self(bytes)
+    }
+
+    /// keeps the first `len` bytes, the rest are zeroed before they're let go
+    pub fn truncate(&mut self, len: usize) {
+        if len < self.0.len() {
+            zeroize(&mut self.0[len..]);
+            self.0.truncate(len);
+        }
+    }
+}
+
+impl From<String> for Secret {
+    fn from(s: String) -> Self {
+        Self::new(s.into_bytes())
+    }
+}
+
+impl std::ops::Deref for Secret {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl std::ops::DerefMut for Secret {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.0
+    }
+}
+
+impl AsRef<[u8]> for Secret {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl Drop for Secret {
+    fn drop(&mut self) {
+        zeroize(&mut self.0);
+    }
+}
+
+impl std::fmt::Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Secret(..)")
+    }
+}
+
+/// overwrites `buf` with zeros, in a way the compiler can't optimize away
+pub(crate) fn zeroize(buf: &mut [u8]) {
+    for b in buf.iter_mut() {
+        unsafe { std::ptr::write_volatile(b, 0) };
+    }
+    std::sync::atomic::compiler_fence(std::sync::atomic::Ordering::SeqCst);
+}
+
+/// keeps `buf` out of the swap, at best effort: it fails past `RLIMIT_MEMLOCK`
+/// the pages are never unlocked, as they may be shared with other secrets
+fn mlock(buf: &[u8]) {
+    if !buf.is_empty() {
+        unsafe { libc::mlock(buf.as_ptr() as *const libc::c_void, buf.len()) };
+    }
+}
 
 #[derive(Debug, Default, Serialize, Deserialize, Clone)]
 pub struct IV(pub [u8; IV_LEN]);
 
+/// authentication tag of the encrypted data
+#[derive(Debug, Default, Serialize, Deserialize, Clone)]
+pub struct Tag(pub [u8; TAG_LEN]);
+
 impl IV {
-    pub fn from_bytes(s: &[u8]) -> Self {
-        let mut buf = [0u8; IV_LEN];
+    /// GCM must never see the same IV twice under a key, so it's always random
+    pub fn random() -> Self {
+        let mut iv = [0; IV_LEN];
+        rand::fill(&mut iv[..]);
+        Self(iv)
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum KdfAlgorithm {
+    Pbkdf2,
+    #[default]
+    Scrypt,
+}
+
+/// parameters of the function deriving the keys from passwords
+/// they are stored in the superblock, headerless volumes take them from the config
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Kdf {
+    pub algorithm: KdfAlgorithm,
+    // number of iterations for pbkdf2, log2(N) for scrypt
+    pub cost: u32,
+    // r and p of scrypt, unused by pbkdf2
+    pub block_size: u32,
+    pub parallelism: u32,
+}
+
+impl Kdf {
+    pub fn pbkdf2(iterations: u32) -> Self {
+        Self {
+            algorithm: KdfAlgorithm::Pbkdf2,
+            cost: iterations,
+            block_size: 0,
+            parallelism: 0,
+        }
+    }
 
-        let len = s.len().min(IV_LEN);
-        buf[..len].copy_from_slice(&s[..len]);
+    pub fn scrypt(log_n: u32, r: u32, p: u32) -> Self {
+        Self {
+            algorithm: KdfAlgorithm::Scrypt,
+            cost: log_n,
+            block_size: r,
+            parallelism: p,
+        }
+    }
+
+    /// uses the recommended parameters in case `cost` isn't specified
+    pub fn new(algorithm: KdfAlgorithm, cost: Option<u32>) -> Self {
+        match algorithm {
+            KdfAlgorithm::Pbkdf2 => Self::pbkdf2(cost.unwrap_or(PBKDF2_ITERATIONS)),
+            KdfAlgorithm::Scrypt => Self::scrypt(cost.unwrap_or(SCRYPT_LOG_N), 8, 1),
+        }
+    }
 
-        Self(buf)
+    /// the parameters come from the volume or the config, `derive` must not choke on them
+    pub fn validate(&self) -> Result<()> {
+        let valid = match self.algorithm {
+            KdfAlgorithm::Pbkdf2 => self.cost > 0,
+            KdfAlgorithm::Scrypt => self.cost < 64 && self.block_size > 0 && self.parallelism > 0,
+        };
+        match valid {
+            true => Ok(()),
+            false => Err(PinoqError::InvalidArgument),
+        }
+    }
+
+    pub fn derive(&self, password: &[u8], salt: &[u8]) -> Result<Key> {
+        self.validate()?;
+        let mut key = Key::default();
+        let out = &mut key.0[..];
+        match self.algorithm {
+            KdfAlgorithm::Pbkdf2 => {
+                pbkdf2_hmac(password, salt, self.cost as _, MessageDigest::sha256(), out)?
+            }
+            KdfAlgorithm::Scrypt => {
+                let (n, r, p) = (
+                    1u64 << self.cost,
+                    self.block_size as u64,
+                    self.parallelism as u64,
+                );
+                // memory needed by openssl's implementation: 128 * r * (N + p + 2)
+                let maxmem = (n + p + 2)
+                    .checked_mul(128 * r)
+                    .ok_or(PinoqError::InvalidArgument)?;
+                scrypt(password, salt, n, r, p, maxmem, out)?
+            }
+        }
+        Ok(key)
     }
 }
 
-pub(crate) fn random_key() -> Key {
-    let mut k = [0; KEY_LEN];
-    rand::fill(&mut k[..]);
-    Key(k)
+impl Default for Kdf {
+    fn default() -> Self {
+        Self::new(KdfAlgorithm::default(), None)
+    }
 }
 
-pub(crate) fn decrypt(encrypted_data: &[u8], key: &Key, iv: &IV) -> Vec<u8> {
-    let cipher = Cipher::aes_256_cbc();
-    let mut decrypter = Crypter::new(cipher, Mode::Decrypt, &key.0, Some(&iv.0)).unwrap();
+/// the key derived from a secret to wrap an aspect
+#[derive(Debug)]
+pub struct WrappingKey {
+    pub salt: [u8; SALT_LEN],
+    pub key: Key,
+}
 
-    let block_size = cipher.block_size();
-    let mut decrypted_data = vec![0; encrypted_data.len() + block_size];
-    let count = decrypter
-        .update(encrypted_data, &mut decrypted_data)
-        .unwrap();
-    let rest = decrypter.finalize(&mut decrypted_data[count..]).unwrap();
-    decrypted_data.truncate(count + rest);
+impl WrappingKey {
+    /// derives a key using a fresh random salt
+    pub fn new(secret: &[u8], kdf: Kdf) -> Result<Self> {
+        let mut salt = [0; SALT_LEN];
+        rand::fill(&mut salt[..]);
+        Self::derive(secret, kdf, salt)
+    }
 
-    decrypted_data
+    pub fn derive(secret: &[u8], kdf: Kdf, salt: [u8; SALT_LEN]) -> Result<Self> {
+        let key = kdf.derive(secret, &salt)?;
+        Ok(Self { salt, key })
+    }
 }
 
-pub(crate) fn encrypt(data: &[u8], key: &Key, iv: &IV) -> Vec<u8> {
-    let cipher = Cipher::aes_256_cbc();
-    let mut encrypter = Crypter::new(cipher, Mode::Encrypt, &key.0, Some(&iv.0)).unwrap();
+pub(crate) fn random_key() -> Key {
+    let mut key = Key::default();
+    rand::fill(&mut key.0[..]);
+    key
+}
 
-    let block_size = cipher.block_size();
-    let mut encrypted_data = vec![0; data.len() + block_size];
-    let count = encrypter.update(data, &mut encrypted_data).unwrap();
-    let rest = encrypter.finalize(&mut encrypted_data[count..]).unwrap();
-    encrypted_data.truncate(count + rest);
+/// `aad` is authenticated along with the data, but not encrypted
+pub(crate) fn decrypt(
+    encrypted_data: &[u8],
+    key: &Key,
+    iv: &IV,
+    aad: &[u8],
+    tag: &Tag,
+) -> Result<Vec<u8>> {
+    let cipher = Cipher::aes_256_gcm();
+    decrypt_aead(cipher, &key.0[..], Some(&iv.0), aad, encrypted_data, &tag.0)
+        .map_err(|_| PinoqError::Authentication)
+}
 
-    encrypted_data
+pub(crate) fn encrypt(data: &[u8], key: &Key, iv: &IV, aad: &[u8]) -> Result<(Vec<u8>, Tag)> {
+    let cipher = Cipher::aes_256_gcm();
+    let mut tag = Tag::default();
+    let encrypted_data = encrypt_aead(cipher, &key.0[..], Some(&iv.0), aad, data, &mut tag.0)?;
+    Ok((encrypted_data, tag))
 }
 
 #[cfg(test)]
@@ -62,27 +283,83 @@ mod tests {
     #[test]
     fn test_encrypt_decrypt_sanity() {
         let data = vec![1, 2, 3, 4];
-        let key = Key([1; KEY_LEN]);
-        let iv = IV::from_bytes("testpass".as_bytes());
+        let key = Key::from_slice(&[1; KEY_LEN]);
+        let iv = IV([2; IV_LEN]);
 
-        let encrypted = encrypt(&data, &key, &iv);
-        assert_eq!(
-            encrypted,
-            vec![38, 18, 161, 119, 20, 132, 125, 92, 211, 96, 187, 79, 89, 52, 133, 49]
-        );
+        let (encrypted, tag) = encrypt(&data, &key, &iv, b"aad").unwrap();
+        assert_eq!(encrypted, vec![6, 212, 202, 77]);
 
-        let decrypted = decrypt(&encrypted, &key, &iv);
+        let decrypted = decrypt(&encrypted, &key, &iv, b"aad", &tag).unwrap();
         assert_eq!(decrypted, data);
     }
 
+    #[test]
+    fn test_authentication() {
+        let data = vec![1, 2, 3, 4];
+        let key = random_key();
+        let iv = IV::random();
+        let (mut encrypted, tag) = encrypt(&data, &key, &iv, b"aad").unwrap();
+
+        let result = decrypt(&encrypted, &random_key(), &iv, b"aad", &tag);
+        assert!(matches!(result, Err(PinoqError::Authentication)));
+        let result = decrypt(&encrypted, &key, &iv, b"other", &tag);
+        assert!(matches!(result, Err(PinoqError::Authentication)));
+
+        encrypted[0] ^= 1;
+        let result = decrypt(&encrypted, &key, &iv, b"aad", &tag);
+        assert!(matches!(result, Err(PinoqError::Authentication)));
+    }
+
     #[test]
     fn test_encryption_length() {
-        // encrypted length should be: ceil(16 * n) * 16
+        // GCM doesn't pad, the tag is kept separately
         let data = vec![6u8; 1020];
         let key = random_key();
-        let iv = IV::from_bytes("testpass".as_bytes());
 
-        let enc = encrypt(&data, &key, &iv);
-        assert_eq!(enc.len(), 1024);
+        let (enc, _) = encrypt(&data, &key, &IV::random(), &[]).unwrap();
+        assert_eq!(enc.len(), 1020);
+    }
+
+    #[test]
+    fn test_kdf() {
+        let salt = [7; SALT_LEN];
+        for kdf in [Kdf::pbkdf2(1000), Kdf::scrypt(10, 8, 1)] {
+            let k1 = kdf.derive(b"password", &salt).unwrap();
+            let k2 = kdf.derive(b"password", &salt).unwrap();
+            assert_eq!(k1.0, k2.0);
+
+            let k3 = kdf.derive(b"passw0rd", &salt).unwrap();
+            assert_ne!(k1.0, k3.0);
+            let k4 = kdf.derive(b"password", &[8; SALT_LEN]).unwrap();
+            assert_ne!(k1.0, k4.0);
+        }
+
+        for kdf in [Kdf::pbkdf2(0), Kdf::scrypt(64, 8, 1), Kdf::scrypt(10, 0, 1)] {
+            assert!(matches!(kdf.validate(), Err(PinoqError::InvalidArgument)));
+            let result = kdf.derive(b"password", &salt);
+            assert!(matches!(result, Err(PinoqError::InvalidArgument)));
+        }
+        assert!(Kdf::scrypt(60, 1 << 30, 1)
+            .derive(b"password", &salt)
+            .is_err());
+
+        let k1 = WrappingKey::new(b"password", Kdf::pbkdf2(1000)).unwrap();
+        let k2 = WrappingKey::new(b"password", Kdf::pbkdf2(1000)).unwrap();
+        assert_ne!(k1.salt, k2.salt);
+        assert_ne!(k1.key.0, k2.key.0);
+    }
+
+    #[test]
+    fn test_secrets() {
+        let key = Key::from_slice(&[0xAB; KEY_LEN]);
+        assert_eq!(format!("{:?}", key), "Key(..)");
+        assert_eq!(key.clone().0, key.0);
+        let secret = Secret::from("password".to_string());
+        assert_eq!(format!("{:?}", secret), "Secret(..)");
+        assert_eq!(&secret[..], b"password");
+
+        let mut buf = vec![0xAB; 64];
+        zeroize(&mut buf);
+        assert!(buf.iter().all(|&b| b == 0));
     }
 }
@@ -8,14 +8,44 @@ pub(crate) enum PinoqError {
     NoEntry,
     #[error("Not a directory")]
     NoDirectory,
+    #[error("File exists")]
+    AlreadyExists,
+    #[error("Is a directory")]
+    IsDirectory,
+    #[error("Directory not empty")]
+    NotEmpty,
+    #[error("Invalid argument")]
+    InvalidArgument,
     #[error("Not enoguh space available")]
     NoEnoughSpace,
-    #[error("IO error: {0}")]
+    #[error("Data doesn't fit in a block")]
+    BlockOverflow,
+    #[error("The directory is full, it holds about 34 entries (see HACKING.md)")]
+    DirectoryFull,
+    #[error("IO error")]
     IO(#[from] std::io::Error),
     #[error("Serialization error")]
     Serialization(#[from] bincode::Error),
     #[error("Invalid Config")]
     InvalidConfig,
+    #[error("Crypto error")]
+    Crypto(#[from] openssl::error::ErrorStack),
+    #[error("Authentication failed, wrong key or tampered data")]
+    Authentication,
+    #[error("Wrong password, or no aspect in this slot")]
+    WrongPassword,
+    #[error("The volume is truncated")]
+    Truncated,
+    #[error("Not a pinoq volume")]
+    BadMagic,
+    #[error("Unsupported volume version")]
+    UnsupportedVersion,
+    #[error("The aspect is being re-keyed, run the re-key again to finish it")]
+    RekeyPending,
+    #[error("The volume is in use, it may be mounted")]
+    Busy,
+    #[error("Passwords don't match")]
+    PasswordMismatch,
 }
 
 impl PinoqError {
@@ -23,9 +53,23 @@ impl PinoqError {
         match self {
             Self::NoEntry => libc::ENOENT,
             Self::NoDirectory => libc::ENOTDIR,
-            Self::NoEnoughSpace => libc::ENOSPC,
-            Self::IO(_) => libc::EIO,
-            _ => -1,
+            Self::AlreadyExists => libc::EEXIST,
+            Self::IsDirectory => libc::EISDIR,
+            Self::NotEmpty => libc::ENOTEMPTY,
+            Self::InvalidArgument | Self::InvalidConfig => libc::EINVAL,
+            Self::NoEnoughSpace | Self::BlockOverflow => libc::ENOSPC,
+            Self::DirectoryFull => libc::EMLINK,
+            Self::WrongPassword | Self::PasswordMismatch => libc::EACCES,
+            Self::Busy => libc::EBUSY,
+            Self::RekeyPending => libc::EAGAIN,
+            // the volume can't be read or doesn't hold what's expected
+            Self::IO(_)
+            | Self::Serialization(_)
+            | Self::Crypto(_)
+            | Self::Authentication
+            | Self::Truncated
+            | Self::BadMagic
+            | Self::UnsupportedVersion => libc::EIO,
         }
     }
 }
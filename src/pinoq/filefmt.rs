@@ -1,16 +1,22 @@
 use std::collections::BTreeMap;
 use std::io::{Cursor, Read, Write};
-use std::time::UNIX_EPOCH;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use crate::pinoq::encryption::*;
-use crate::pinoq::error::Result;
+use crate::pinoq::error::{PinoqError, Result};
 
 use bitvec::{order::Lsb0, vec::BitVec};
 use fuser::{FileAttr, FileType};
 use serde::{Deserialize, Serialize};
 
 pub(crate) const BLOCK_SIZE: usize = 1 << 10;
+// every block is encrypted as exactly this many bytes, whatever it holds
+pub(crate) const BLOCK_PAYLOAD_SIZE: usize = BLOCK_SIZE - IV_LEN - TAG_LEN;
 const MAGIC: u32 = 0x504E4F51u32;
+const INODE_VERSION: u8 = 1;
+const VOLUME_VERSION: u32 = 1;
+// every volume has this many aspect slots, the unused ones hold random data
+pub(crate) const ASPECT_SLOTS: u32 = 8;
 
 pub trait PinoqSerialize: Sized {
     fn serialize_into<W: Write>(&self, w: W) -> Result<()>;
@@ -24,41 +30,51 @@ where
     let mut buf = Cursor::new(Vec::new());
     t.serialize_into(&mut buf)?;
 
-    let iv = IV::from_bytes(&n.to_be_bytes());
-    let enc_buf = encrypt(&buf.into_inner(), key, &iv);
+    // pad to a fixed size, so a rewritten block doesn't reveal how much it holds
+    let mut buf = buf.into_inner();
+    if buf.len() > BLOCK_PAYLOAD_SIZE {
+        return Err(PinoqError::BlockOverflow);
+    }
+    buf.resize(BLOCK_PAYLOAD_SIZE, 0);
+
+    // the block number is authenticated, so blocks can't be swapped
+    let iv = IV::random();
+    let (data, tag) = encrypt(&buf, key, &iv, &n.to_be_bytes())?;
 
-    Ok(EncryptedBlock(enc_buf))
+    Ok(EncryptedBlock { iv, tag, data })
 }
 
 pub fn from_encrypted_block<T>(eb: &EncryptedBlock, key: &Key, n: u32) -> Result<T>
 where
     T: PinoqSerialize,
 {
-    let iv = IV::from_bytes(&n.to_be_bytes());
-    let buf = decrypt(&eb.0, key, &iv);
+    let buf = decrypt(&eb.data, key, &eb.iv, &n.to_be_bytes(), &eb.tag)?;
     let buf = Cursor::new(buf);
     T::deserialize_from(buf)
 }
 
+/// the only part of a volume stored in clear
+/// it tells neither the number of aspects nor anything about their content
 #[derive(Debug, Default, Serialize, Deserialize)]
 pub struct SuperBlock {
     pub magic: u32,
-    pub aspects: u32,
-    pub blocks: u32,
-    pub uid: u32,
-    pub gid: u32,
+    pub version: u32,
+    // shared by all the aspect slots, so it doesn't tell the used ones apart
+    pub kdf: Kdf,
 }
 
 impl SuperBlock {
-    pub fn new(aspects: u32, blocks: u32, uid: u32, gid: u32) -> Self {
+    pub fn new(kdf: Kdf) -> Self {
         Self {
             magic: MAGIC,
-            aspects,
-            blocks,
-            uid,
-            gid,
+            version: VOLUME_VERSION,
+            kdf,
         }
     }
+
+    pub fn size_of() -> usize {
+        bincode::serialized_size(&Self::default()).unwrap() as usize
+    }
 }
 
 impl PinoqSerialize for SuperBlock {
@@ -69,57 +85,135 @@ impl PinoqSerialize for SuperBlock {
         bincode::serialize_into(w, self).map_err(|e| e.into())
     }
 
-    fn deserialize_from<R>(r: R) -> Result<Self>
+    /// checks the magic and the version before parsing the rest
+    fn deserialize_from<R>(mut r: R) -> Result<Self>
     where
         R: Read,
     {
-        bincode::deserialize_from(r).map_err(|e| e.into())
+        let (magic, version): (u32, u32) = bincode::deserialize_from(&mut r).map_err(truncated)?;
+        if magic != MAGIC {
+            return Err(PinoqError::BadMagic);
+        }
+        if version != VOLUME_VERSION {
+            return Err(PinoqError::UnsupportedVersion);
+        }
+        // parameters this version can't derive keys with
+        let kdf: Kdf = bincode::deserialize_from(r).map_err(truncated)?;
+        kdf.validate().map_err(|_| PinoqError::UnsupportedVersion)?;
+        Ok(Self {
+            magic,
+            version,
+            kdf,
+        })
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// a volume that ends in the middle of a structure is reported as such
+fn truncated(e: bincode::Error) -> PinoqError {
+    match *e {
+        bincode::ErrorKind::Io(ref io) if io.kind() == std::io::ErrorKind::UnexpectedEof => {
+            PinoqError::Truncated
+        }
+        _ => e.into(),
+    }
+}
+
+/// an aspect slot: salt + iv + tag + data
+/// there's no length nor anything else in clear, so a used slot can't be
+/// told apart from one filled with random data
+#[derive(Debug)]
 pub struct EncryptedAspect {
-    // to encrypt/decrypt the aspect
-    pub key: Key,
+    // to derive the wrapping key from the password
+    pub salt: [u8; SALT_LEN],
+    // fresh for every write
+    pub iv: IV,
+    pub tag: Tag,
     pub encrypted_data: Vec<u8>,
 }
 
 impl EncryptedAspect {
+    /// size of a slot of a volume with `n` blocks
     pub fn size_of(n: u32) -> usize {
-        // FIXME: calculate the length instead of using hardcoded numbers
-        ((n as usize) / 8) + 88
+        SALT_LEN + IV_LEN + TAG_LEN + Aspect::payload_len(n)
     }
-}
 
-impl PinoqSerialize for EncryptedAspect {
-    fn serialize_into<W>(&self, w: W) -> Result<()>
+    /// derives the key protecting this aspect from `secret`
+    pub fn wrapping_key(&self, secret: &[u8], kdf: Kdf) -> Result<WrappingKey> {
+        WrappingKey::derive(secret, kdf, self.salt)
+    }
+
+    pub fn serialize_into<W>(&self, mut w: W) -> Result<()>
     where
         W: Write,
     {
-        bincode::serialize_into(w, self).map_err(|e| e.into())
+        w.write_all(&self.salt)?;
+        w.write_all(&self.iv.0)?;
+        w.write_all(&self.tag.0)?;
+        w.write_all(&self.encrypted_data).map_err(|e| e.into())
     }
 
-    fn deserialize_from<R>(r: R) -> Result<Self>
+    /// reads the slot of a volume with `n` blocks
+    pub fn deserialize_from<R>(mut r: R, n: u32) -> Result<Self>
     where
         R: Read,
     {
-        bincode::deserialize_from(r).map_err(|e| e.into())
+        let mut ea = Self {
+            salt: [0; SALT_LEN],
+            iv: IV::default(),
+            tag: Tag::default(),
+            encrypted_data: vec![0; Aspect::payload_len(n)],
+        };
+        r.read_exact(&mut ea.salt)?;
+        r.read_exact(&mut ea.iv.0)?;
+        r.read_exact(&mut ea.tag.0)?;
+        r.read_exact(&mut ea.encrypted_data)?;
+        Ok(ea)
     }
 }
 
-#[derive(Debug, Default, Clone)]
+/// how the blocks of an aspect are picked
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum Allocation {
+    /// the first free block, predictable but handy for tests
+    FirstFit,
+    /// uniformly among the free blocks, so the layout leaks nothing
+    #[default]
+    Random,
+}
+
+impl Allocation {
+    pub(crate) fn from_u8(value: u8) -> Result<Self> {
+        match value {
+            0 => Ok(Self::FirstFit),
+            1 => Ok(Self::Random),
+            _ => Err(PinoqError::UnsupportedVersion),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
 pub struct Aspect {
     // to encrypt/decrypt the blocks
     pub key: Key,
     pub root_block: u32,
+    // owner of the root directory
+    pub uid: u32,
+    pub gid: u32,
+    pub allocation: Allocation,
+    // replaces `key` once every block is re-encrypted with it
+    pub next_key: Option<Key>,
     pub block_map: BitVec<u8, Lsb0>,
 }
 
 impl Aspect {
-    pub fn new(blocks: u32) -> Self {
+    pub fn new(blocks: u32, uid: u32, gid: u32) -> Self {
         Self {
             key: random_key(),
             root_block: 0xFFFFFFFF, // we consider 0xFFFFFFFF as uninitialized
+            uid,
+            gid,
+            allocation: Allocation::default(),
+            next_key: None,
             block_map: BitVec::repeat(false, blocks as _),
         }
     }
@@ -128,73 +222,141 @@ impl Aspect {
         self.root_block != 0xFFFFFFFF
     }
 
-    pub fn serialize(&self) -> Vec<u8> {
-        let mut buf = vec![];
+    /// magic + key + root block + uid + gid + allocation + next key + block map
+    fn payload_len(n: u32) -> usize {
+        4 + KEY_LEN + 4 + 4 + 4 + 1 + 1 + KEY_LEN + (n as usize).div_ceil(8)
+    }
+
+    /// the keys are in there, so it's zeroed once dropped
+    pub fn serialize(&self) -> Secret {
+        // never reallocated, so no copy is left behind
+        let mut buf = Vec::with_capacity(Self::payload_len(self.block_map.len() as _));
 
-        buf.extend_from_slice(&self.key.0);
+        buf.extend_from_slice(&MAGIC.to_be_bytes());
+        buf.extend_from_slice(&self.key.0[..]);
         buf.extend_from_slice(&self.root_block.to_be_bytes());
-        buf.extend_from_slice(&self.block_map.as_raw_slice());
+        buf.extend_from_slice(&self.uid.to_be_bytes());
+        buf.extend_from_slice(&self.gid.to_be_bytes());
+        buf.push(self.allocation as u8);
+        // always takes the same room, pending or not
+        match &self.next_key {
+            Some(key) => {
+                buf.push(1);
+                buf.extend_from_slice(&key.0[..]);
+            }
+            None => {
+                buf.push(0);
+                buf.extend_from_slice(&[0; KEY_LEN]);
+            }
+        }
+        buf.extend_from_slice(self.block_map.as_raw_slice());
 
-        buf
+        Secret::new(buf)
     }
 
-    pub fn from_encrypted_aspect(ea: EncryptedAspect, password: &str) -> Result<Self> {
-        let iv = IV::from_bytes(password.as_bytes());
-        let decrypted = decrypt(&ea.encrypted_data, &ea.key, &iv);
+    /// `n` is the index of the aspect, authenticated along with the data
+    pub fn from_encrypted_aspect(ea: EncryptedAspect, wk: &WrappingKey, n: u32) -> Result<Self> {
+        let aad = n.to_be_bytes();
+        let decrypted = Secret::new(decrypt(&ea.encrypted_data, &wk.key, &ea.iv, &aad, &ea.tag)?);
+        if decrypted.len() < Self::payload_len(0) {
+            return Err(PinoqError::Truncated);
+        }
+
+        let (magic, rest) = decrypted.split_at(4);
+        if magic != MAGIC.to_be_bytes() {
+            return Err(PinoqError::BadMagic);
+        }
+
+        let (key, rest) = rest.split_at(KEY_LEN);
 
-        let mut kbuf = [0u8; KEY_LEN];
-        kbuf.copy_from_slice(&decrypted[..KEY_LEN]);
+        let read_u32 = |buf: &[u8]| u32::from_be_bytes(buf[..4].try_into().unwrap());
 
-        const BUF_LEN: usize = 4;
-        let mut bbuf = [0u8; BUF_LEN];
-        bbuf.copy_from_slice(&decrypted[KEY_LEN..KEY_LEN + BUF_LEN]);
+        let (next, map) = rest[13..].split_at(1 + KEY_LEN);
+        let next_key = match next[0] {
+            0 => None,
+            _ => Some(Key::from_slice(&next[1..])),
+        };
 
         Ok(Self {
-            key: Key(kbuf), // FIXME
-            root_block: u32::from_be_bytes(bbuf),
-            block_map: BitVec::<u8, Lsb0>::from_slice(&decrypted[KEY_LEN + BUF_LEN..]),
+            key: Key::from_slice(key),
+            root_block: read_u32(rest),
+            uid: read_u32(&rest[4..]),
+            gid: read_u32(&rest[8..]),
+            allocation: Allocation::from_u8(rest[12])?,
+            next_key,
+            block_map: BitVec::<u8, Lsb0>::from_slice(map),
         })
     }
 
-    pub fn to_encrypted_aspect(&self, password: &str) -> EncryptedAspect {
-        let key = random_key();
-        // TODO: currently we're using password as IV (init vector)
-        // should use PBKDF in the future and fill the IV with random data
+    pub fn to_encrypted_aspect(&self, wk: &WrappingKey, n: u32) -> Result<EncryptedAspect> {
         let encoded = self.serialize();
-        let iv = IV::from_bytes(password.as_bytes());
-        let encrypted_data = encrypt(encoded.as_slice(), &key, &iv);
+        let iv = IV::random();
+        let (encrypted_data, tag) = encrypt(&encoded, &wk.key, &iv, &n.to_be_bytes())?;
 
-        EncryptedAspect {
-            key,
+        Ok(EncryptedAspect {
+            salt: wk.salt,
+            iv,
+            tag,
             encrypted_data,
-        }
+        })
     }
 }
 
-// TODO: must raise an error in case the inner data length is larger than BLOCK_SIZE
-// otherwise we'll have overlapping blocks which leads to data corruption
-#[derive(Debug, Serialize, Deserialize)]
-pub struct EncryptedBlock(pub Vec<u8>);
+/// takes exactly `BLOCK_SIZE` bytes on the disk: iv + tag + data
+/// there's no length in clear, the data is always `BLOCK_PAYLOAD_SIZE` bytes
+#[derive(Debug)]
+pub struct EncryptedBlock {
+    pub iv: IV,
+    pub tag: Tag,
+    pub data: Vec<u8>,
+}
 
 impl PinoqSerialize for EncryptedBlock {
-    fn serialize_into<W>(&self, w: W) -> Result<()>
+    fn serialize_into<W>(&self, mut w: W) -> Result<()>
     where
         W: Write,
     {
-        // bincode: 4 bytes for len + data
-        assert!(
-            self.0.len() <= BLOCK_SIZE - std::mem::size_of::<usize>(),
-            "block overflow {}",
-            self.0.len()
-        );
-        bincode::serialize_into(w, self).map_err(|e| e.into())
+        assert_eq!(self.data.len(), BLOCK_PAYLOAD_SIZE, "invalid block size");
+        w.write_all(&self.iv.0)?;
+        w.write_all(&self.tag.0)?;
+        w.write_all(&self.data).map_err(|e| e.into())
     }
 
-    fn deserialize_from<R>(r: R) -> Result<Self>
+    fn deserialize_from<R>(mut r: R) -> Result<Self>
     where
         R: Read,
     {
-        bincode::deserialize_from(r).map_err(|e| e.into())
+        let mut eb = Self {
+            iv: IV::default(),
+            tag: Tag::default(),
+            data: vec![0; BLOCK_PAYLOAD_SIZE],
+        };
+        r.read_exact(&mut eb.iv.0)?;
+        r.read_exact(&mut eb.tag.0)?;
+        r.read_exact(&mut eb.data)?;
+        Ok(eb)
+    }
+}
+
+/// the plaintext of a block whatever it holds, padding included
+#[derive(Debug)]
+pub struct RawBlock(pub Vec<u8>);
+
+impl PinoqSerialize for RawBlock {
+    fn serialize_into<W>(&self, mut w: W) -> Result<()>
+    where
+        W: Write,
+    {
+        w.write_all(&self.0).map_err(|e| e.into())
+    }
+
+    fn deserialize_from<R>(mut r: R) -> Result<Self>
+    where
+        R: Read,
+    {
+        let mut buf = vec![];
+        r.read_to_end(&mut buf)?;
+        Ok(Self(buf))
     }
 }
 
@@ -205,6 +367,15 @@ pub struct Block {
     pub data: Vec<u8>,
 }
 
+impl Default for Block {
+    fn default() -> Self {
+        Self {
+            next_block: 0xFFFFFFFF,
+            data: vec![],
+        }
+    }
+}
+
 impl PinoqSerialize for Block {
     fn serialize_into<W>(&self, w: W) -> Result<()>
     where
@@ -229,6 +400,27 @@ pub struct INode {
     pub uid: u32,
     pub gid: u32,
     pub data_block: u32,
+    // stored as a versioned extension after the fields above
+    #[serde(skip)]
+    pub times: INodeTimes,
+}
+
+/// the first extension of `INode` (version 1)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct INodeTimes {
+    pub atime: SystemTime,
+    pub mtime: SystemTime,
+    pub ctime: SystemTime,
+}
+
+impl Default for INodeTimes {
+    fn default() -> Self {
+        Self {
+            atime: UNIX_EPOCH,
+            mtime: UNIX_EPOCH,
+            ctime: UNIX_EPOCH,
+        }
+    }
 }
 
 impl INode {
@@ -242,7 +434,15 @@ impl INode {
     }
 
     pub fn is_dir(&self) -> bool {
-        self.mode & libc::S_IFDIR != 0
+        self.mode & libc::S_IFMT == libc::S_IFDIR
+    }
+
+    pub fn perm(&self) -> u16 {
+        (self.mode & 0o7777) as _
+    }
+
+    pub fn set_perm(&mut self, perm: u32) {
+        self.mode = (self.mode & libc::S_IFMT) | (perm & 0o7777);
     }
 
     // pub fn from_attr(attrs: &FileAttr) -> Self {
@@ -258,24 +458,23 @@ impl INode {
     //     }
     // }
 
-    pub fn as_attr(&self, n: u32) -> FileAttr {
-        let kind = match self.mode & libc::S_IFDIR {
-            0 => FileType::RegularFile,
-            _ => FileType::Directory,
+    pub fn as_attr(&self, ino: u64) -> FileAttr {
+        let (kind, nlink) = match self.is_dir() {
+            false => (FileType::RegularFile, 1),
+            true => (FileType::Directory, 2),
         };
 
         FileAttr {
-            ino: n as _,
+            ino,
             size: self.size as _,
             blocks: 1, // TODO:
-            // TODO: should we present the real datetime in deniable encryptions?
-            atime: UNIX_EPOCH,
-            mtime: UNIX_EPOCH,
-            ctime: UNIX_EPOCH,
+            atime: self.times.atime,
+            mtime: self.times.mtime,
+            ctime: self.times.ctime,
             crtime: UNIX_EPOCH,
             kind,
-            perm: 0o755, // TODO:
-            nlink: 1,    // TODO:
+            perm: self.perm(),
+            nlink,
             uid: self.uid,
             gid: self.gid,
             rdev: 0,
@@ -286,26 +485,50 @@ impl INode {
 }
 
 impl PinoqSerialize for INode {
-    fn serialize_into<W>(&self, w: W) -> Result<()>
+    fn serialize_into<W>(&self, mut w: W) -> Result<()>
     where
         W: Write,
     {
-        bincode::serialize_into(w, self).map_err(|e| e.into())
+        bincode::serialize_into(&mut w, self)?;
+        bincode::serialize_into(&mut w, &INODE_VERSION)?;
+        bincode::serialize_into(w, &self.times).map_err(|e| e.into())
     }
 
-    fn deserialize_from<R>(r: R) -> Result<Self>
+    fn deserialize_from<R>(mut r: R) -> Result<Self>
     where
         R: Read,
     {
-        bincode::deserialize_from(r).map_err(|e| e.into())
+        let mut node: Self = bincode::deserialize_from(&mut r)?;
+        // inodes written before versioning end right after the base fields
+        // newer versions only append to the end, so it's safe to read what we know
+        let version: u8 = bincode::deserialize_from(&mut r).unwrap_or(0);
+        match version {
+            0 => {
+                // permissions weren't stored at all
+                if node.perm() == 0 {
+                    node.set_perm(0o755);
+                }
+            }
+            _ => node.times = bincode::deserialize_from(r)?,
+        }
+        Ok(node)
     }
 }
 
+/// the entries of a directory, kept in a single block
+/// so a directory holds about 34 entries with 16-character names
 #[derive(Debug, Default, Serialize, Deserialize)]
 pub struct Dir {
     pub entries: BTreeMap<String, u32>,
 }
 
+impl Dir {
+    /// whether the entries still fit in a block
+    pub fn fits(&self) -> bool {
+        bincode::serialized_size(self).is_ok_and(|n| n as usize <= BLOCK_PAYLOAD_SIZE)
+    }
+}
+
 impl PinoqSerialize for Dir {
     fn serialize_into<W>(&self, w: W) -> Result<()>
     where
@@ -331,7 +554,7 @@ mod tests {
         let mut dir = Dir::default();
         dir.entries.insert("name".to_string(), 123);
 
-        let key = Key([1; KEY_LEN]);
+        let key = Key::from_slice(&[1; KEY_LEN]);
         let enc_block = to_encrypted_block(&dir, &key, 69).unwrap();
 
         let dir = from_encrypted_block::<Dir>(&enc_block, &key, 69).unwrap();
@@ -339,6 +562,98 @@ mod tests {
 
         // invalid block number
         let result = from_encrypted_block::<Dir>(&enc_block, &key, 88);
-        assert!(result.is_err());
+        assert!(matches!(result, Err(PinoqError::Authentication)));
+
+        // tampered block
+        let mut enc_block = enc_block;
+        let mut buf = vec![];
+        enc_block.serialize_into(&mut buf).unwrap();
+        assert_eq!(buf.len(), BLOCK_SIZE);
+        enc_block.data[0] ^= 1;
+        let result = from_encrypted_block::<Dir>(&enc_block, &key, 69);
+        assert!(matches!(result, Err(PinoqError::Authentication)));
+    }
+
+    #[test]
+    fn test_inode_versions() {
+        let mut node = INode::new(libc::S_IFREG | 0o640, 1000, 1000);
+        node.times.mtime = UNIX_EPOCH + std::time::Duration::from_secs(1234);
+
+        let mut buf = vec![];
+        node.serialize_into(&mut buf).unwrap();
+        let node = INode::deserialize_from(&buf[..]).unwrap();
+        assert_eq!(node.perm(), 0o640);
+        assert!(!node.is_dir());
+        assert_eq!(node.as_attr(2).mtime, node.times.mtime);
+        assert_ne!(node.times.mtime, UNIX_EPOCH);
+
+        // an inode written before the versioned extension
+        let legacy = INode::new(libc::S_IFDIR, 1000, 1000);
+        let buf = bincode::serialize(&legacy).unwrap();
+        let node = INode::deserialize_from(&buf[..]).unwrap();
+        assert!(node.is_dir());
+        assert_eq!(node.perm(), 0o755);
+        assert_eq!(node.times.mtime, UNIX_EPOCH);
+    }
+
+    #[test]
+    fn test_encrypted_aspect() {
+        let aspect = Aspect::new(1000, 1000, 1000);
+        let wk = WrappingKey::new(b"password", Kdf::pbkdf2(1000)).unwrap();
+
+        let ea1 = aspect.to_encrypted_aspect(&wk, 0).unwrap();
+        let ea2 = aspect.to_encrypted_aspect(&wk, 0).unwrap();
+        assert_ne!(ea1.iv.0, ea2.iv.0);
+        assert_ne!(ea1.encrypted_data, ea2.encrypted_data);
+        // the aspect key never appears in clear
+        assert!(!ea1
+            .encrypted_data
+            .windows(KEY_LEN)
+            .any(|w| w == &aspect.key.0[..]));
+
+        let mut buf = vec![];
+        ea1.serialize_into(&mut buf).unwrap();
+        assert_eq!(buf.len(), EncryptedAspect::size_of(1000));
+        let ea1 = EncryptedAspect::deserialize_from(&buf[..], 1000).unwrap();
+
+        let kdf = Kdf::pbkdf2(1000);
+        let wrong = ea1.wrapping_key(b"passw0rd", kdf).unwrap();
+        let result = Aspect::from_encrypted_aspect(ea2, &wrong, 0);
+        assert!(matches!(result, Err(PinoqError::Authentication)));
+
+        let wk = ea1.wrapping_key(b"password", kdf).unwrap();
+        let decrypted = Aspect::from_encrypted_aspect(ea1, &wk, 0).unwrap();
+        assert_eq!(decrypted.key.0, aspect.key.0);
+        assert_eq!(decrypted.root_block, aspect.root_block);
+        assert_eq!(decrypted.uid, 1000);
+        assert_eq!(decrypted.allocation, Allocation::Random);
+        assert_eq!(decrypted.block_map.len(), 1000);
+    }
+
+    #[test]
+    fn test_encrypted_block_unlinkable() {
+        let key = random_key();
+        let small = Block::default();
+        let large = Block {
+            next_block: 1,
+            data: vec![1; 512],
+        };
+
+        // same content, same block: nothing in common but the length
+        let eb1 = to_encrypted_block(&large, &key, 5).unwrap();
+        let eb2 = to_encrypted_block(&large, &key, 5).unwrap();
+        assert_ne!(eb1.iv.0, eb2.iv.0);
+        assert_ne!(eb1.data[..16], eb2.data[..16]);
+
+        // the amount of data isn't visible either
+        let eb3 = to_encrypted_block(&small, &key, 5).unwrap();
+        assert_eq!(eb1.data.len(), eb3.data.len());
+
+        let too_large = Block {
+            next_block: 1,
+            data: vec![1; BLOCK_SIZE],
+        };
+        let result = to_encrypted_block(&too_large, &key, 5);
+        assert!(matches!(result, Err(PinoqError::BlockOverflow)));
     }
 }
@@ -1,80 +1,163 @@
 use std::collections::{BTreeMap, HashMap};
 use std::ffi::OsStr;
-use std::fs::OpenOptions;
+use std::fs::{File, OpenOptions};
 use std::io::{prelude::*, Cursor, SeekFrom};
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use crate::pinoq::{
     config::Config,
+    decrypt_aspect,
+    encryption::{Kdf, WrappingKey},
     error::{PinoqError, Result},
     filefmt::{
-        from_encrypted_block, to_encrypted_block, Aspect, Block, Dir, EncryptedBlock, INode,
-        PinoqSerialize, SuperBlock, BLOCK_SIZE,
+        from_encrypted_block, to_encrypted_block, Allocation, Aspect, Block, Dir, EncryptedBlock,
+        INode, PinoqSerialize, SuperBlock, ASPECT_SLOTS, BLOCK_PAYLOAD_SIZE, BLOCK_SIZE,
     },
+    open_volume, Geometry,
 };
 
 use bitvec::{order::Lsb0, vec::BitVec};
 use fuser::{
-    FileAttr, Filesystem, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory, ReplyEntry, ReplyOpen,
-    ReplyWrite, Request, TimeOrNow,
+    FileAttr, Filesystem, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory, ReplyEmpty,
+    ReplyEntry, ReplyOpen, ReplyWrite, Request, TimeOrNow,
 };
-use memmap::MmapMut;
+use memmap::{MmapMut, MmapOptions};
 
 const TTL: Duration = Duration::from_secs(1);
+// the amount of file data each block holds
+// `next_block` and the length of the data take the rest
+const RAW_BLK_SIZE: usize = BLOCK_PAYLOAD_SIZE - 12;
+// the random blocks drawn before scanning for a free one
+const RANDOM_ALLOCATION_TRIES: usize = 64;
 
 #[derive(Debug, Default)]
 struct FDManager {
-    file_decs: HashMap<u64, FileDescriptor>,
+    file_descs: HashMap<u64, FileDescriptor>,
+    next_fh: u64,
 }
 
 impl FDManager {
-    pub fn get(&self, fd: u64) -> Option<&FileDescriptor> {
-        self.file_decs.get(&fd)
+    pub fn open(&mut self, inode: u64) -> u64 {
+        self.next_fh += 1;
+        let fd = FileDescriptor {
+            inode,
+            cursor: None,
+        };
+        self.file_descs.insert(self.next_fh, fd);
+        self.next_fh
+    }
+
+    pub fn release(&mut self, fh: u64) {
+        self.file_descs.remove(&fh);
     }
 
-    pub fn insert(&mut self, fd: u64, val: FileDescriptor) {
-        self.file_decs.insert(fd, val);
+    /// where to start walking the chain of `inode` to reach its `index`th block
+    pub fn cursor(&self, fh: Option<u64>, inode: u64, index: usize) -> Option<(usize, u32)> {
+        let fd = self.file_descs.get(&fh?)?;
+        fd.cursor.filter(|&(i, _)| fd.inode == inode && i <= index)
+    }
+
+    pub fn set_cursor(&mut self, fh: Option<u64>, cursor: (usize, u32)) {
+        if let Some(fd) = fh.and_then(|fh| self.file_descs.get_mut(&fh)) {
+            fd.cursor = Some(cursor);
+        }
+    }
+
+    /// to call once blocks of `inode` are freed, the cursors may point to them
+    pub fn forget(&mut self, inode: u64) {
+        for fd in self.file_descs.values_mut().filter(|fd| fd.inode == inode) {
+            fd.cursor = None;
+        }
     }
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug)]
 struct FileDescriptor {
-    next_block: Option<u32>,
+    inode: u64,
+    // the index and the number of the last block reached in the chain,
+    // so sequential reads and writes don't walk it from the start every time
+    cursor: Option<(usize, u32)>,
 }
 
 pub struct PinoqFs {
     config: Config,
+    // kept open for its lock, see `open_volume`
+    _disk: File,
     mmap: MmapMut,
-    sblock: SuperBlock,
+    // derived from the size of the volume
+    geometry: Geometry,
     aspect: Aspect,
-    // should be constructed only after decrypting all the aspects
+    wrapping_key: WrappingKey,
+    // every block known to be in use, see `construct_block_map`
     block_map: BitVec<u8, Lsb0>,
+    // the block maps of the protected aspects, by aspect
+    protected: Vec<(u32, BitVec<u8, Lsb0>)>,
     fd_manager: FDManager,
 }
 
+/// an inconsistency found by `PinoqFs::check`, blocks are the ones of the aspect
+#[derive(Debug, PartialEq)]
+pub enum Problem {
+    /// the root can't be read, nothing under it can be found
+    MissingRoot,
+    /// an entry of the directory at `dir` pointing to something that isn't an inode,
+    /// or to an inode already found elsewhere (a cycle)
+    DanglingEntry { dir: u32, name: String },
+    /// the content of the inode can't be read past `block`
+    BrokenChain { inode: u32, block: u32 },
+    /// in use, but not reachable from the root
+    LeakedBlock(u32),
+    /// reachable from the root, but free for the allocator
+    UnmarkedBlock(u32),
+    /// also in use by a protected aspect, which can't be written to fix it
+    SharedBlock { block: u32, aspect: u32 },
+}
+
+impl Problem {
+    /// whether a repair fixes it, `problems` being everything found along with it
+    pub fn is_repairable(&self, problems: &[Problem]) -> bool {
+        match self {
+            // only a block this aspect doesn't use anyway can be given up
+            Problem::SharedBlock { block, .. } => problems.contains(&Problem::LeakedBlock(*block)),
+            _ => true,
+        }
+    }
+}
+
 impl PinoqFs {
     pub fn new(config: Config) -> Result<Self> {
-        let disk = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .open(&config.disk)?;
-        let mmap = unsafe { MmapMut::map_mut(&disk)? };
+        let mut fs = Self::open(config)?;
+        fs.init_root()?;
+        Ok(fs)
+    }
+
+    /// same as `new`, without giving a root to an aspect that has none yet
+    pub fn open(config: Config) -> Result<Self> {
+        // the geometry is checked before mapping, an empty file can't be mapped
+        // the length is given, the one of a block device isn't known to mmap
+        let secret = config.current.secret()?;
+        let (disk, kdf, geometry) = open_volume(&config, &secret)?;
+        let mmap = unsafe { MmapOptions::new().len(geometry.len()).map_mut(&disk)? };
         let mut cursor = Cursor::new(&mmap);
 
-        let sblock = SuperBlock::deserialize_from(&mut cursor)?;
-        let offset = crate::pinoq::get_aspect_offset(sblock.blocks, config.current.aspect);
-        let aspect = crate::pinoq::decrypt_aspect(&mut cursor, offset, &config.current.password)?;
+        let (aspect, wrapping_key) =
+            decrypt_aspect(&mut cursor, &geometry, config.current.aspect, kdf, &secret)?;
+        if aspect.next_key.is_some() {
+            return Err(PinoqError::RekeyPending);
+        }
 
         let mut fs = PinoqFs {
             config,
+            _disk: disk,
             mmap,
-            sblock,
+            geometry,
             aspect,
+            wrapping_key,
             block_map: BitVec::new(),
+            protected: vec![],
             fd_manager: FDManager::default(),
         };
-        fs.construct_block_map()?;
-        fs.init_root()?;
+        fs.construct_block_map(kdf, &secret)?;
 
         Ok(fs)
     }
@@ -83,16 +166,54 @@ impl PinoqFs {
         let mut disk = OpenOptions::new()
             .read(true)
             .open(path)
-            .map_err(|e| PinoqError::IO(e))?;
+            .map_err(PinoqError::IO)?;
         SuperBlock::deserialize_from(&mut disk)
     }
 
-    fn construct_block_map(&mut self) -> Result<()> {
-        log::debug!("Constructing Block Map for {} Aspects", self.sblock.aspects);
-        self.block_map = BitVec::repeat(false, self.sblock.blocks as _);
-        for i in 0..self.sblock.aspects {
-            let aspect = self.get_aspect(i)?;
-            self.block_map |= aspect.block_map;
+    /// the other aspects are locked by their own passwords, so only the blocks
+    /// of the mounted one, of the protected ones (never written) and of the ones
+    /// sharing `secret` are known
+    fn construct_block_map(&mut self, kdf: Kdf, secret: &[u8]) -> Result<()> {
+        log::debug!("Constructing Block Map");
+        self.block_map = self.aspect.block_map.clone();
+
+        let current = self.config.current.aspect;
+        for protected in &self.config.protect {
+            if protected.aspect == current {
+                continue;
+            }
+            let cursor = Cursor::new(&self.mmap);
+            let secret = protected.secret()?;
+            let (aspect, _) =
+                decrypt_aspect(cursor, &self.geometry, protected.aspect, kdf, &secret)?;
+            self.block_map |= &aspect.block_map;
+            self.protected.push((protected.aspect, aspect.block_map));
+        }
+
+        // unlocked by the same secret, so they're kept without being listed
+        let listed: Vec<_> = self.config.protect.iter().map(|c| c.aspect).collect();
+        for n in (0..ASPECT_SLOTS).filter(|n| *n != current && !listed.contains(n)) {
+            let cursor = Cursor::new(&self.mmap);
+            if let Ok((aspect, _)) = decrypt_aspect(cursor, &self.geometry, n, kdf, secret) {
+                self.block_map |= &aspect.block_map;
+                self.protected.push((n, aspect.block_map));
+            }
+        }
+
+        if self.config.protect.is_empty() {
+            log::warn!(
+                "Only the blocks of aspect {} and of the ones sharing its password are known, \
+                 writing may overwrite the data of the other aspects. List them under \
+                 [[protect]] to keep them safe, at the cost of giving away their passwords \
+                 to whoever makes you mount the volume",
+                current
+            );
+        } else {
+            let protected: Vec<_> = self.config.protect.iter().map(|c| c.aspect).collect();
+            log::warn!(
+                "Aspects {:?} are protected, the ones not listed may still be overwritten",
+                protected
+            );
         }
         Ok(())
     }
@@ -100,16 +221,69 @@ impl PinoqFs {
     /// make sure to store the current aspect after calling this function
     /// as it only modifies the aspect's block_map in-memory
     fn allocate_block(&mut self) -> Result<usize> {
-        let index = self
-            .find_free_block()
-            .ok_or_else(|| PinoqError::NoEnoughSpace)?;
+        let index = self.find_free_block().ok_or(PinoqError::NoEnoughSpace)?;
         self.block_map.set(index, true);
         self.aspect.block_map.set(index, true);
         Ok(index)
     }
 
+    /// make sure to store the current aspect after calling this function
+    /// as it only modifies the aspect's block_map in-memory
+    fn free_block(&mut self, n: u32) {
+        self.block_map.set(n as _, false);
+        self.aspect.block_map.set(n as _, false);
+    }
+
+    /// frees the blocks holding the content of `node`
+    /// directories keep their entries in a single block, files in a chain of blocks
+    fn free_data_blocks(&mut self, node: &INode) -> Result<()> {
+        if node.is_dir() {
+            self.free_block(node.data_block);
+            return Ok(());
+        }
+        self.free_chain(node.data_block)
+    }
+
+    /// frees the chain of blocks starting from `n`
+    fn free_chain(&mut self, n: u32) -> Result<()> {
+        let mut next_block = n;
+        // a chain can't be longer than the volume, don't loop forever on a corrupted one
+        for _ in 0..self.geometry.blocks {
+            if next_block == 0xFFFFFFFF {
+                break;
+            }
+            let blk = self.get_from_block::<Block>(next_block)?;
+            self.free_block(next_block);
+            next_block = blk.next_block;
+        }
+        Ok(())
+    }
+
     fn find_free_block(&self) -> Option<usize> {
-        self.block_map.iter().position(|x| !*x)
+        match self.aspect.allocation {
+            Allocation::FirstFit => self.block_map.first_zero(),
+            Allocation::Random => {
+                let len = self.block_map.len();
+                if len == 0 {
+                    return None;
+                }
+                // drawing until a free block comes up is cheap as long as the map isn't
+                // nearly full, counting the free blocks on every call would make filling
+                // the volume quadratic
+                for _ in 0..RANDOM_ALLOCATION_TRIES {
+                    let index = rand::random_range(0..len);
+                    if !self.block_map[index] {
+                        return Some(index);
+                    }
+                }
+                // nearly full, scan from a random block and wrap around
+                let start = rand::random_range(0..len);
+                self.block_map[start..]
+                    .first_zero()
+                    .map(|i| start + i)
+                    .or_else(|| self.block_map[..start].first_zero())
+            }
+        }
     }
 
     // TODO: move to mkfs
@@ -121,19 +295,25 @@ impl PinoqFs {
             return Ok(());
         }
 
-        // TODO: allocate random blocks
         let root_block_index = self.allocate_block()?;
         let data_block_index = self.allocate_block()?;
         self.aspect.root_block = root_block_index as _;
 
-        let mut root_node = INode::new(libc::S_IFDIR, self.sblock.uid, self.sblock.gid);
+        let mut root_node = INode::new(libc::S_IFDIR | 0o755, self.aspect.uid, self.aspect.gid);
         root_node.block_size = BLOCK_SIZE as _;
         root_node.data_block = data_block_index as _;
-
-        let directory = Dir::default();
+        root_node.times.atime = self.now();
+        root_node.times.mtime = self.now();
+        root_node.times.ctime = self.now();
+
+        // root is its own parent
+        let mut directory = Dir::default();
+        directory
+            .entries
+            .insert("..".to_string(), root_block_index as _);
         self.store_to_block(&root_node, root_block_index as _)?;
         self.store_to_block(&directory, data_block_index as _)?;
-        self.store_aspect(self.aspect.clone(), self.config.current.aspect)
+        self.store_aspect()
     }
 
     fn get_directory_content(&self, inode: u64) -> Result<BTreeMap<String, u32>> {
@@ -147,43 +327,314 @@ impl PinoqFs {
         }
 
         let entries = self.get_directory_content(inode.data_block as _)?;
-        match entries.get(name.to_str().unwrap()) {
+        match entries.get(name.to_str().ok_or(PinoqError::InvalidArgument)?) {
             Some(&n) => {
                 let inode = self.get_from_block::<INode>(n)?;
-                Ok(inode.as_attr(n))
+                Ok(inode.as_attr(self.to_fuse_inode(n)))
             }
             None => Err(PinoqError::NoEntry),
         }
     }
 
-    fn create_entry(&mut self, inode: u64, name: &OsStr) -> Result<FileAttr> {
-        let mut node = INode::new(libc::S_IFREG, self.sblock.uid, self.sblock.gid);
+    /// creates a regular file or a directory (depending on `mode`) inside `inode`
+    fn create_entry(
+        &mut self,
+        inode: u64,
+        name: &OsStr,
+        mode: libc::mode_t,
+        uid: u32,
+        gid: u32,
+    ) -> Result<FileAttr> {
+        let mut parent = self.get_from_block::<INode>(inode as _)?;
+        if !parent.is_dir() {
+            return Err(PinoqError::NoDirectory);
+        }
+
+        let mut dir = self.get_from_block::<Dir>(parent.data_block)?;
+        let name = name.to_str().ok_or(PinoqError::InvalidArgument)?;
+        if dir.entries.contains_key(name) {
+            return Err(PinoqError::AlreadyExists);
+        }
+
+        // the number of the entry doesn't change the size, check before allocating anything
+        dir.entries.insert(name.to_owned(), 0xFFFFFFFF);
+        if !dir.fits() {
+            return Err(PinoqError::DirectoryFull);
+        }
+
+        let mut node = INode::new(mode, uid, gid);
         node.block_size = BLOCK_SIZE as _;
         node.data_block = 0xFFFFFFFF;
+        node.times.atime = self.now();
+        node.times.mtime = self.now();
+        node.times.ctime = self.now();
 
         let node_block_index = self.allocate_block()?;
 
-        let parent = self.get_from_block::<INode>(inode as _)?;
-        let mut dir = self.get_from_block::<Dir>(parent.data_block)?;
+        if node.is_dir() {
+            let data_block_index = self.allocate_block()?;
+            node.data_block = data_block_index as _;
+
+            let mut content = Dir::default();
+            content.entries.insert("..".to_string(), inode as _);
+            self.store_to_block(&content, data_block_index as _)?;
+        }
 
-        let name = name.to_str().unwrap();
         dir.entries.insert(name.to_owned(), node_block_index as _);
+        parent.times.mtime = self.now();
+        parent.times.ctime = self.now();
         self.store_to_block(&parent, inode as _)?;
         self.store_to_block(&dir, parent.data_block as _)?;
 
-        self.store_aspect(self.aspect.clone(), self.config.current.aspect)?;
+        self.store_aspect()?;
         self.store_to_block(&node, node_block_index as _)?;
 
-        Ok(node.as_attr(node_block_index as _))
+        Ok(node.as_attr(self.to_fuse_inode(node_block_index as _)))
     }
 
-    fn list_entries(&self, inode: u64) -> Result<Vec<(u64, fuser::FileType, String)>> {
+    /// removes `name` from `inode` and reclaims all of its blocks
+    /// directories must be empty
+    fn remove_entry(&mut self, inode: u64, name: &OsStr, is_dir: bool) -> Result<()> {
+        let parent = self.get_from_block::<INode>(inode as _)?;
+        if !parent.is_dir() {
+            return Err(PinoqError::NoDirectory);
+        }
+
+        let mut dir = self.get_from_block::<Dir>(parent.data_block)?;
+        let name = name.to_str().ok_or(PinoqError::InvalidArgument)?;
+        let n = *dir.entries.get(name).ok_or(PinoqError::NoEntry)?;
+
+        let node = self.get_from_block::<INode>(n)?;
+        match (is_dir, node.is_dir()) {
+            (true, false) => return Err(PinoqError::NoDirectory),
+            (false, true) => return Err(PinoqError::IsDirectory),
+            _ => {}
+        }
+        if node.is_dir() {
+            let content = self.get_directory_content(node.data_block as _)?;
+            if content.keys().any(|k| k != "..") {
+                return Err(PinoqError::NotEmpty);
+            }
+        }
+
+        dir.entries.remove(name);
+        self.store_to_block(&dir, parent.data_block)?;
+        self.touch(inode)?;
+
+        self.free_data_blocks(&node)?;
+        self.free_block(n);
+        self.fd_manager.forget(n as _);
+        self.store_aspect()
+    }
+
+    /// moves `name` of `inode` to `new_name` of `new_inode`
+    /// supports `RENAME_NOREPLACE` and `RENAME_EXCHANGE` flags
+    fn rename_entry(
+        &mut self,
+        inode: u64,
+        name: &OsStr,
+        new_inode: u64,
+        new_name: &OsStr,
+        flags: u32,
+    ) -> Result<()> {
+        let exchange = flags & libc::RENAME_EXCHANGE != 0;
+        let no_replace = flags & libc::RENAME_NOREPLACE != 0;
+        if exchange && no_replace {
+            return Err(PinoqError::InvalidArgument);
+        }
+
         let parent = self.get_from_block::<INode>(inode as _)?;
-        let dir_entries = self.get_directory_content(parent.data_block as _)?;
+        let new_parent = self.get_from_block::<INode>(new_inode as _)?;
+        if !parent.is_dir() || !new_parent.is_dir() {
+            return Err(PinoqError::NoDirectory);
+        }
+
+        let mut dir = self.get_from_block::<Dir>(parent.data_block)?;
+        let mut new_dir = self.get_from_block::<Dir>(new_parent.data_block)?;
+        let name = name.to_str().ok_or(PinoqError::InvalidArgument)?;
+        let new_name = new_name.to_str().ok_or(PinoqError::InvalidArgument)?;
+
+        let n = *dir.entries.get(name).ok_or(PinoqError::NoEntry)?;
+        let node = self.get_from_block::<INode>(n)?;
+        let target = new_dir.entries.get(new_name).copied();
+
+        // a directory can't be moved into itself
+        if node.is_dir() && self.is_ancestor(n as _, new_inode)? {
+            return Err(PinoqError::InvalidArgument);
+        }
+
+        if exchange {
+            let t = target.ok_or(PinoqError::NoEntry)?;
+            let target_node = self.get_from_block::<INode>(t)?;
+            if target_node.is_dir() && self.is_ancestor(t as _, inode)? {
+                return Err(PinoqError::InvalidArgument);
+            }
+
+            if inode == new_inode {
+                dir.entries.insert(name.to_owned(), t);
+                dir.entries.insert(new_name.to_owned(), n);
+                self.store_to_block(&dir, parent.data_block)?;
+                self.touch(inode)?;
+            } else {
+                new_dir.entries.insert(new_name.to_owned(), n);
+                dir.entries.insert(name.to_owned(), t);
+                self.store_to_block(&new_dir, new_parent.data_block)?;
+                self.store_to_block(&dir, parent.data_block)?;
+                self.set_parent(&node, new_inode)?;
+                self.set_parent(&target_node, inode)?;
+                self.touch(inode)?;
+                self.touch(new_inode)?;
+            }
+            return Ok(());
+        }
+
+        let replaced = match target {
+            Some(t) if t == n => return Ok(()),
+            Some(_) if no_replace => return Err(PinoqError::AlreadyExists),
+            Some(t) => {
+                let target_node = self.get_from_block::<INode>(t)?;
+                match (node.is_dir(), target_node.is_dir()) {
+                    (true, false) => return Err(PinoqError::NoDirectory),
+                    (false, true) => return Err(PinoqError::IsDirectory),
+                    _ => {}
+                }
+                if target_node.is_dir() {
+                    let content = self.get_directory_content(target_node.data_block as _)?;
+                    if content.keys().any(|k| k != "..") {
+                        return Err(PinoqError::NotEmpty);
+                    }
+                }
+                Some((t, target_node))
+            }
+            None => None,
+        };
+
+        // the new name is stored before removing the old one
+        // so an interrupted rename never loses the entry
+        if inode == new_inode {
+            dir.entries.insert(new_name.to_owned(), n);
+            dir.entries.remove(name);
+            if !dir.fits() {
+                return Err(PinoqError::DirectoryFull);
+            }
+            self.store_to_block(&dir, parent.data_block)?;
+            self.touch(inode)?;
+        } else {
+            new_dir.entries.insert(new_name.to_owned(), n);
+            if !new_dir.fits() {
+                return Err(PinoqError::DirectoryFull);
+            }
+            self.store_to_block(&new_dir, new_parent.data_block)?;
+            dir.entries.remove(name);
+            self.store_to_block(&dir, parent.data_block)?;
+            self.set_parent(&node, new_inode)?;
+            self.touch(inode)?;
+            self.touch(new_inode)?;
+        }
+
+        if let Some((t, target_node)) = replaced {
+            self.free_data_blocks(&target_node)?;
+            self.free_block(t);
+            self.fd_manager.forget(t as _);
+            self.store_aspect()?;
+        }
+        Ok(())
+    }
+
+    /// marks the content of `inode` as modified
+    fn touch(&mut self, inode: u64) -> Result<()> {
+        let mut node = self.get_from_block::<INode>(inode as _)?;
+        node.times.mtime = self.now();
+        node.times.ctime = self.now();
+        self.store_to_block(&node, inode as _)
+    }
+
+    /// updates the metadata of `inode`, `None` leaves the attribute untouched
+    fn set_attr(
+        &mut self,
+        inode: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        atime: Option<SystemTime>,
+        mtime: Option<SystemTime>,
+    ) -> Result<INode> {
+        let mut node = self.get_from_block::<INode>(inode as _)?;
+        if mode.is_none() && uid.is_none() && gid.is_none() && atime.is_none() && mtime.is_none() {
+            return Ok(node);
+        }
+
+        if let Some(mode) = mode {
+            node.set_perm(mode);
+        }
+        node.uid = uid.unwrap_or(node.uid);
+        node.gid = gid.unwrap_or(node.gid);
+        if let Some(atime) = atime {
+            node.times.atime = self.timestamp(atime);
+        }
+        if let Some(mtime) = mtime {
+            node.times.mtime = self.timestamp(mtime);
+        }
+        node.times.ctime = self.now();
+
+        self.store_to_block(&node, inode as _)?;
+        Ok(node)
+    }
+
+    /// the time to be stored on the disk
+    /// everything stays at the epoch in case timestamps are hidden
+    fn timestamp(&self, t: SystemTime) -> SystemTime {
+        match self.config.hide_timestamps {
+            true => UNIX_EPOCH,
+            false => t,
+        }
+    }
+
+    fn now(&self) -> SystemTime {
+        self.timestamp(SystemTime::now())
+    }
+
+    /// points `..` of a directory to `parent`, does nothing for files
+    fn set_parent(&mut self, node: &INode, parent: u64) -> Result<()> {
+        if !node.is_dir() {
+            return Ok(());
+        }
+        let mut dir = self.get_from_block::<Dir>(node.data_block)?;
+        dir.entries.insert("..".to_string(), parent as _);
+        self.store_to_block(&dir, node.data_block)
+    }
+
+    /// whether `ancestor` is `inode` itself or one of its parents
+    fn is_ancestor(&self, ancestor: u64, inode: u64) -> Result<bool> {
+        let mut n = inode;
+        for _ in 0..self.geometry.blocks {
+            if n == ancestor {
+                return Ok(true);
+            }
+            let node = self.get_from_block::<INode>(n as _)?;
+            match self.get_directory_content(node.data_block as _)?.get("..") {
+                Some(&p) if p as u64 != n => n = p as _,
+                // reached the root
+                _ => return Ok(false),
+            }
+        }
+        Ok(false)
+    }
+
+    /// lists the entries of `inode` using the aspect's block indices
+    fn list_entries(&self, inode: u64) -> Result<Vec<(u64, fuser::FileType, String)>> {
+        let node = self.get_from_block::<INode>(inode as _)?;
+        if !node.is_dir() {
+            return Err(PinoqError::NoDirectory);
+        }
+
+        let mut dir_entries = self.get_directory_content(node.data_block as _)?;
+        // volumes created before nested directories have no `..` in their root
+        let parent = dir_entries.remove("..").map_or(inode, |n| n as _);
 
         let mut entries = vec![
             (inode, fuser::FileType::Directory, ".".to_string()),
-            (1, fuser::FileType::Directory, "..".to_string()),
+            (parent, fuser::FileType::Directory, "..".to_string()),
         ];
 
         for (name, i) in dir_entries {
@@ -199,82 +650,451 @@ impl PinoqFs {
         Ok(entries)
     }
 
-    fn write(&mut self, ino: u64, fh: u64, data: &[u8]) -> Result<usize> {
-        const RAW_BLK_SIZE: usize = BLOCK_SIZE - 32;
-
-        let mut next_block = self.allocate_block()?;
+    /// writes `data` at byte `offset` of the file
+    /// every block of a file except the last one is full, so an offset always maps
+    /// to `offset / RAW_BLK_SIZE`th block of the chain
+    /// the chain is walked from the cursor of `fh` when it's not past the offset
+    fn write(&mut self, ino: u64, fh: Option<u64>, offset: u64, data: &[u8]) -> Result<usize> {
+        if data.is_empty() {
+            return Ok(0);
+        }
 
-        let fd = match self.fd_manager.get(fh) {
-            Some(n) => n,
-            None => {
-                self.fd_manager
-                    .insert(fh, FileDescriptor { next_block: None });
-                &FileDescriptor { next_block: None }
+        let offset = offset as usize;
+        let first_block = offset / RAW_BLK_SIZE;
+
+        let mut inode = self.get_from_block::<INode>(ino as _)?;
+        // the aspect is only stored when its block map changes, rewriting its slot
+        // for nothing would tell which slot is in use
+        let mut allocated = false;
+        // freshly allocated blocks hold garbage, don't try to decrypt them
+        // every block before the cursor is full, so it's fine to start from there
+        let (from, mut n, mut blk, mut dirty) = match self.fd_manager.cursor(fh, ino, first_block) {
+            Some((i, n)) => (i, n, self.get_from_block::<Block>(n)?, false),
+            None if inode.data_block == 0xFFFFFFFF => {
+                let n = self.allocate_block()? as u32;
+                inode.data_block = n;
+                allocated = true;
+                (0, n, Block::default(), true)
             }
+            None => (
+                0,
+                inode.data_block,
+                self.get_from_block::<Block>(inode.data_block)?,
+                false,
+            ),
         };
-        match fd.next_block {
-            Some(n) => {
-                let mut b = self.get_from_block::<Block>(n as _)?;
-                b.next_block = next_block as _;
-                self.store_to_block(&b, n)?;
-            }
-            None => {
-                let mut inode = self.get_from_block::<INode>(ino as _)?;
-                inode.data_block = next_block as _;
-                self.store_to_block(&inode, ino as _)?;
-            }
-        }
-
-        let mut chunks = data.chunks(RAW_BLK_SIZE).peekable();
-        while let Some(chunk) = chunks.next() {
-            let current_block = next_block;
-            next_block = match chunks.peek() {
-                None => {
-                    self.fd_manager.insert(
-                        fh,
-                        FileDescriptor {
-                            next_block: Some(next_block as _),
-                        },
-                    );
-                    0xFFFFFFFF
+
+        // only the blocks that change are stored, the ones walked through keep their IV
+        let mut written = 0;
+        for i in from.. {
+            if i >= first_block {
+                let start = match i == first_block {
+                    true => offset % RAW_BLK_SIZE,
+                    false => 0,
+                };
+                let len = (RAW_BLK_SIZE - start).min(data.len() - written);
+                if blk.data.len() < start + len {
+                    blk.data.resize(start + len, 0);
                 }
-                Some(_) => self.allocate_block()?,
-            };
+                blk.data[start..start + len].copy_from_slice(&data[written..written + len]);
+                written += len;
+                dirty = true;
+            } else if blk.data.len() < RAW_BLK_SIZE {
+                // writing past the end of file, fill the gap with zeros
+                blk.data.resize(RAW_BLK_SIZE, 0);
+                dirty = true;
+            }
 
-            let blk = Block {
-                data: chunk.to_vec(),
-                next_block: next_block as _,
+            let done = written == data.len();
+            let fresh = !done && blk.next_block == 0xFFFFFFFF;
+            if fresh {
+                blk.next_block = self.allocate_block()? as _;
+                allocated = true;
+                dirty = true;
+            }
+            if dirty {
+                self.store_to_block(&blk, n)?;
+            }
+
+            if done {
+                self.fd_manager.set_cursor(fh, (i, n));
+                break;
+            }
+            n = blk.next_block;
+            blk = match fresh {
+                true => Block::default(),
+                false => self.get_from_block::<Block>(n)?,
             };
-            self.store_to_block(&blk, current_block as _)?;
+            dirty = fresh;
+        }
+
+        inode.size = inode.size.max(offset + written);
+        inode.times.mtime = self.now();
+        inode.times.ctime = self.now();
+        self.store_to_block(&inode, ino as _)?;
+        if allocated {
+            self.store_aspect()?;
+        }
+        Ok(written)
+    }
+
+    /// shrinks or grows the file to `size` bytes
+    /// growing fills the file with zeros, shrinking frees the blocks past the new end
+    fn truncate(&mut self, ino: u64, size: u64) -> Result<()> {
+        let mut inode = self.get_from_block::<INode>(ino as _)?;
+        if inode.is_dir() {
+            return Err(PinoqError::IsDirectory);
         }
 
-        self.store_aspect(self.aspect.clone(), self.config.current.aspect)?;
-        Ok(data.len())
+        let size = size as usize;
+        if size > inode.size {
+            // write takes care of the gap and the size
+            self.write(ino, None, (size - 1) as _, &[0])?;
+            return Ok(());
+        }
+
+        self.fd_manager.forget(ino);
+        if size == 0 {
+            self.free_chain(inode.data_block)?;
+            inode.data_block = 0xFFFFFFFF;
+        } else {
+            // the last block that survives
+            let last_block = (size - 1) / RAW_BLK_SIZE;
+            let mut n = inode.data_block;
+            for _ in 0..last_block {
+                n = self.get_from_block::<Block>(n)?.next_block;
+            }
+
+            let mut blk = self.get_from_block::<Block>(n)?;
+            self.free_chain(blk.next_block)?;
+            blk.data.truncate(size - last_block * RAW_BLK_SIZE);
+            blk.next_block = 0xFFFFFFFF;
+            self.store_to_block(&blk, n)?;
+        }
+
+        inode.size = size;
+        inode.times.mtime = self.now();
+        inode.times.ctime = self.now();
+        self.store_to_block(&inode, ino as _)?;
+        self.store_aspect()
     }
 
-    fn read(&mut self, ino: u64, fh: u64, _offset: u64) -> Result<Vec<u8>> {
-        let fd = self.fd_manager.get(fh).unwrap();
-        let next_block = match fd.next_block {
-            Some(n) => n,
-            None => {
-                let inode = self.get_from_block::<INode>(ino as _)?;
-                inode.data_block
+    /// reads at most `size` bytes starting from byte `offset` of the file
+    /// the chain is walked from the cursor of `fh` when it's not past the offset
+    fn read(&mut self, ino: u64, fh: Option<u64>, offset: u64, size: usize) -> Result<Vec<u8>> {
+        let inode = self.get_from_block::<INode>(ino as _)?;
+
+        let offset = offset as usize;
+        let first_block = offset / RAW_BLK_SIZE;
+        let size = size.min(inode.size.saturating_sub(offset));
+
+        let mut data = vec![];
+        let (mut i, mut n) = self
+            .fd_manager
+            .cursor(fh, ino, first_block)
+            .unwrap_or((0, inode.data_block));
+        while n != 0xFFFFFFFF && data.len() < size {
+            let blk = self.get_from_block::<Block>(n)?;
+            self.fd_manager.set_cursor(fh, (i, n));
+            if i >= first_block {
+                let start = match i == first_block {
+                    true => offset % RAW_BLK_SIZE,
+                    false => 0,
+                };
+                if start >= blk.data.len() {
+                    break;
+                }
+                let len = (blk.data.len() - start).min(size - data.len());
+                data.extend_from_slice(&blk.data[start..start + len]);
             }
-        };
+            n = blk.next_block;
+            i += 1;
+        }
+
+        Ok(data)
+    }
 
-        if next_block == 0xFFFFFFFF {
+    /// the inode at `path`, whose components are separated by `/` starting from the root
+    pub fn resolve(&self, path: &str) -> Result<u64> {
+        if !self.aspect.has_root_block() {
+            return Err(PinoqError::NoEntry);
+        }
+        let mut inode = self.aspect.root_block as u64;
+        for name in path.split('/').filter(|c| !c.is_empty() && *c != ".") {
+            let attr = self.lookup_name(inode, OsStr::new(name))?;
+            inode = self.convert_inode_index(attr.ino);
+        }
+        Ok(inode)
+    }
+
+    /// the directory holding `path`, along with the name of `path` in it
+    fn resolve_parent<'a>(&self, path: &'a str) -> Result<(u64, &'a str)> {
+        let path = path.trim_end_matches('/');
+        let (parent, name) = path.rsplit_once('/').unwrap_or(("", path));
+        if name.is_empty() || name == "." || name == ".." {
+            return Err(PinoqError::InvalidArgument);
+        }
+        Ok((self.resolve(parent)?, name))
+    }
+
+    pub fn metadata(&self, path: &str) -> Result<INode> {
+        self.get_from_block::<INode>(self.resolve(path)? as _)
+    }
+
+    /// the entries of the directory at `path`, without `.` and `..`
+    pub fn read_dir(&self, path: &str) -> Result<Vec<(String, INode)>> {
+        // an aspect that was never written to has no root yet, it's empty
+        let root = path.split('/').all(|c| c.is_empty() || c == ".");
+        if root && !self.aspect.has_root_block() {
             return Ok(vec![]);
         }
+        let entries = self.list_entries(self.resolve(path)?)?;
+        entries
+            .into_iter()
+            .skip(2)
+            .map(|(n, _, name)| Ok((name, self.get_from_block::<INode>(n as _)?)))
+            .collect()
+    }
 
-        let blk = self.get_from_block::<Block>(next_block)?;
-        self.fd_manager.insert(
-            fh,
-            FileDescriptor {
-                next_block: Some(blk.next_block as _),
-            },
-        );
+    /// copies the content of the file at `path` to `w`
+    pub fn read_file<W>(&mut self, path: &str, mut w: W) -> Result<()>
+    where
+        W: Write,
+    {
+        const CHUNK_SIZE: usize = 1 << 20;
+
+        let ino = self.resolve(path)?;
+        if self.get_from_block::<INode>(ino as _)?.is_dir() {
+            return Err(PinoqError::IsDirectory);
+        }
+        self.with_fh(ino, |fs, fh| {
+            let mut offset = 0;
+            loop {
+                let data = fs.read(ino, Some(fh), offset, CHUNK_SIZE)?;
+                if data.is_empty() {
+                    return Ok(());
+                }
+                w.write_all(&data)?;
+                offset += data.len() as u64;
+            }
+        })
+    }
+
+    /// replaces the content of the file at `path` with everything read from `r`
+    /// the file is created with `perm` if it doesn't exist
+    pub fn write_file<R>(&mut self, path: &str, perm: u32, mut r: R) -> Result<()>
+    where
+        R: Read,
+    {
+        const CHUNK_SIZE: usize = 1 << 20;
+
+        let (parent, name) = self.resolve_parent(path)?;
+        let ino = match self.lookup_name(parent, OsStr::new(name)) {
+            Ok(attr) => {
+                let ino = self.convert_inode_index(attr.ino);
+                self.truncate(ino, 0)?;
+                ino
+            }
+            Err(PinoqError::NoEntry) => {
+                let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+                let mode = libc::S_IFREG | (perm & 0o7777);
+                let attr = self.create_entry(parent, OsStr::new(name), mode, uid, gid)?;
+                self.convert_inode_index(attr.ino)
+            }
+            Err(e) => return Err(e),
+        };
 
-        Ok(blk.data)
+        let mut buf = vec![0; CHUNK_SIZE];
+        self.with_fh(ino, |fs, fh| {
+            let mut offset = 0;
+            loop {
+                let n = r.read(&mut buf)?;
+                if n == 0 {
+                    return Ok(());
+                }
+                fs.write(ino, Some(fh), offset, &buf[..n])?;
+                offset += n as u64;
+            }
+        })
+    }
+
+    /// runs `f` with a file handle of `ino`, released afterwards
+    fn with_fh<T, F>(&mut self, ino: u64, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut Self, u64) -> Result<T>,
+    {
+        let fh = self.fd_manager.open(ino);
+        let result = f(self, fh);
+        self.fd_manager.release(fh);
+        result
+    }
+
+    pub fn create_dir(&mut self, path: &str, perm: u32) -> Result<()> {
+        let (parent, name) = self.resolve_parent(path)?;
+        let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+        let mode = libc::S_IFDIR | (perm & 0o7777);
+        self.create_entry(parent, OsStr::new(name), mode, uid, gid)?;
+        Ok(())
+    }
+
+    /// removes the file or the directory at `path`
+    /// a directory must be empty unless it's `recursive`
+    pub fn remove(&mut self, path: &str, recursive: bool) -> Result<()> {
+        let (parent, name) = self.resolve_parent(path)?;
+        self.remove_tree(parent, name, recursive)
+    }
+
+    fn remove_tree(&mut self, parent: u64, name: &str, recursive: bool) -> Result<()> {
+        let attr = self.lookup_name(parent, OsStr::new(name))?;
+        let is_dir = attr.kind == fuser::FileType::Directory;
+        if is_dir && recursive {
+            let inode = self.convert_inode_index(attr.ino);
+            for (_, _, child) in self.list_entries(inode)?.into_iter().skip(2) {
+                self.remove_tree(inode, &child, true)?;
+            }
+        }
+        self.remove_entry(parent, OsStr::new(name), is_dir)
+    }
+
+    /// walks the aspect from its root and cross-checks the blocks found with its block map
+    /// `repair` fixes everything but the blocks shared with protected aspects: orphans are
+    /// freed, broken chains truncated, and a missing root recreated (empty, nothing under
+    /// it can be found anymore)
+    pub fn check(&mut self, repair: bool) -> Result<Vec<Problem>> {
+        let mut problems = vec![];
+        // every block is reached once, so a cycle shows up as a block reached twice
+        let mut reached: BitVec<u8, Lsb0> = BitVec::repeat(false, self.geometry.blocks as _);
+        // directories whose content is lost, given an empty one once the free blocks are known
+        let mut lost_dirs = vec![];
+
+        let root = self.aspect.root_block;
+        let mut stack = vec![];
+        if self.aspect.has_root_block() {
+            match self.unreached_block::<INode>(root, &reached) {
+                Some(node) if node.is_dir() => {
+                    reached.set(root as _, true);
+                    stack.push((root, root));
+                }
+                _ => problems.push(Problem::MissingRoot),
+            }
+        }
+
+        while let Some((n, parent)) = stack.pop() {
+            let mut node = self.get_from_block::<INode>(n)?;
+            if node.is_dir() {
+                let Some(mut dir) = self.unreached_block::<Dir>(node.data_block, &reached) else {
+                    problems.push(Problem::BrokenChain {
+                        inode: n,
+                        block: node.data_block,
+                    });
+                    lost_dirs.push((n, parent));
+                    continue;
+                };
+                reached.set(node.data_block as _, true);
+
+                let mut dangling = vec![];
+                for (name, &child) in dir.entries.iter().filter(|(k, _)| *k != "..") {
+                    match self.unreached_block::<INode>(child, &reached) {
+                        Some(_) => {
+                            reached.set(child as _, true);
+                            stack.push((child, n));
+                        }
+                        None => dangling.push(name.clone()),
+                    }
+                }
+                for name in &dangling {
+                    dir.entries.remove(name);
+                    problems.push(Problem::DanglingEntry {
+                        dir: n,
+                        name: name.clone(),
+                    });
+                }
+                if repair && !dangling.is_empty() {
+                    self.store_to_block(&dir, node.data_block)?;
+                }
+                continue;
+            }
+
+            let mut last: Option<(u32, Block)> = None;
+            let mut len = 0;
+            let mut b = node.data_block;
+            while b != 0xFFFFFFFF {
+                let Some(blk) = self.unreached_block::<Block>(b, &reached) else {
+                    problems.push(Problem::BrokenChain { inode: n, block: b });
+                    if repair {
+                        match last {
+                            Some((l, mut blk)) => {
+                                blk.next_block = 0xFFFFFFFF;
+                                self.store_to_block(&blk, l)?;
+                            }
+                            None => node.data_block = 0xFFFFFFFF,
+                        }
+                        node.size = node.size.min(len);
+                        self.store_to_block(&node, n)?;
+                    }
+                    break;
+                };
+                reached.set(b as _, true);
+                len += blk.data.len();
+                let next = blk.next_block;
+                last = Some((b, blk));
+                b = next;
+            }
+        }
+
+        let owned = self.aspect.block_map.clone();
+        for (aspect, map) in &self.protected {
+            for b in (map.clone() & &owned).iter_ones() {
+                let (block, aspect) = (b as _, *aspect);
+                problems.push(Problem::SharedBlock { block, aspect });
+            }
+        }
+        for b in (reached.clone() & !owned.clone()).iter_ones() {
+            problems.push(Problem::UnmarkedBlock(b as _));
+            if repair {
+                self.block_map.set(b, true);
+                self.aspect.block_map.set(b, true);
+            }
+        }
+        for b in (owned & !reached).iter_ones() {
+            problems.push(Problem::LeakedBlock(b as _));
+            if repair {
+                self.free_block(b as _);
+            }
+        }
+        // a freed block may still be in use by a protected aspect
+        for (_, map) in &self.protected {
+            self.block_map |= map;
+        }
+
+        if !repair || problems.is_empty() {
+            return Ok(problems);
+        }
+        for (n, parent) in lost_dirs {
+            let mut node = self.get_from_block::<INode>(n)?;
+            node.data_block = self.allocate_block()? as _;
+            let mut dir = Dir::default();
+            dir.entries.insert("..".to_string(), parent);
+            self.store_to_block(&dir, node.data_block)?;
+            self.store_to_block(&node, n)?;
+        }
+        if problems.contains(&Problem::MissingRoot) {
+            self.aspect.root_block = 0xFFFFFFFF;
+            self.init_root()?;
+        }
+        self.store_aspect()?;
+        Ok(problems)
+    }
+
+    /// `n`, unless it's out of the volume, already reached, or not a `T` of this aspect
+    fn unreached_block<T>(&self, n: u32, reached: &BitVec<u8, Lsb0>) -> Option<T>
+    where
+        T: PinoqSerialize,
+    {
+        if n >= self.geometry.blocks || reached[n as usize] {
+            return None;
+        }
+        self.get_from_block::<T>(n).ok()
     }
 
     fn store_to_block<T>(&mut self, t: &T, n: u32) -> Result<()>
@@ -286,7 +1106,7 @@ impl PinoqFs {
         let mut cursor = Cursor::new(self.mmap.as_mut());
         cursor
             .seek(SeekFrom::Start(offset as _))
-            .map_err(|e| PinoqError::IO(e))?;
+            .map_err(PinoqError::IO)?;
 
         let eb = to_encrypted_block(t, &self.aspect.key, n)?;
         eb.serialize_into(&mut cursor)
@@ -299,7 +1119,7 @@ impl PinoqFs {
         let mut cursor = Cursor::new(&self.mmap);
         cursor
             .seek(SeekFrom::Start(self.get_block_offset(n) as _))
-            .map_err(|e| PinoqError::IO(e))?;
+            .map_err(PinoqError::IO)?;
 
         let eb = EncryptedBlock::deserialize_from(cursor)?;
         from_encrypted_block::<T>(&eb, &self.aspect.key, n)
@@ -307,37 +1127,33 @@ impl PinoqFs {
 
     /// fuse returns `1` for root inode
     /// we need to convert that to the aspect's specific root inode
+    /// other inodes are shifted by 2, as `0` is invalid and `1` is reserved in fuse
     fn convert_inode_index(&self, n: u64) -> u64 {
         if n == 1 {
             self.aspect.root_block as _
         } else {
-            // n - 1 // indices start from 1 in fuse
-            n
+            n - 2
         }
     }
 
-    fn get_aspect(&self, n: u32) -> Result<Aspect> {
-        let offset = self.get_aspect_offset(n);
-        let cursor = Cursor::new(&self.mmap);
-        // TODO: provide a way to ask for each aspect's password
-        crate::pinoq::decrypt_aspect(cursor, offset, &self.config.current.password)
+    /// the reverse of `convert_inode_index`
+    fn to_fuse_inode(&self, n: u32) -> u64 {
+        if n == self.aspect.root_block {
+            1
+        } else {
+            n as u64 + 2
+        }
     }
 
-    fn store_aspect(&mut self, aspect: Aspect, n: u32) -> Result<()> {
-        let offset = self.get_aspect_offset(n);
+    fn store_aspect(&mut self) -> Result<()> {
+        let n = self.config.current.aspect;
         let cursor = Cursor::new(self.mmap.as_mut());
-        // TODO: provide a way to ask for each aspect's password
-        crate::pinoq::encrypt_aspect(cursor, offset, aspect, &self.config.current.password)
+        crate::pinoq::encrypt_aspect(cursor, &self.geometry, n, &self.aspect, &self.wrapping_key)
     }
 
     #[inline]
     fn get_block_offset(&self, n: u32) -> usize {
-        crate::pinoq::get_block_offset(self.sblock.aspects, self.sblock.blocks, n)
-    }
-
-    #[inline]
-    fn get_aspect_offset(&self, n: u32) -> usize {
-        crate::pinoq::get_aspect_offset(self.sblock.blocks, n)
+        self.geometry.block_offset(n)
     }
 }
 
@@ -368,7 +1184,8 @@ impl Filesystem for PinoqFs {
         };
 
         for (i, entry) in entries.into_iter().enumerate().skip(offset as usize) {
-            if reply.add(entry.0, (i + 1) as i64, entry.1, entry.2) {
+            let ino = self.to_fuse_inode(entry.0 as _);
+            if reply.add(ino, (i + 1) as i64, entry.1, entry.2) {
                 break;
             }
         }
@@ -377,9 +1194,9 @@ impl Filesystem for PinoqFs {
     }
 
     fn getattr(&mut self, _req: &Request, ino: u64, _fh: Option<u64>, reply: ReplyAttr) {
-        let ino = self.convert_inode_index(ino);
-        match self.get_from_block::<INode>(ino as u32) {
-            Ok(node) => reply.attr(&TTL, &node.as_attr(ino as _)),
+        let inode = self.convert_inode_index(ino);
+        match self.get_from_block::<INode>(inode as u32) {
+            Ok(node) => reply.attr(&TTL, &node.as_attr(ino)),
             Err(_) => reply.error(libc::ENOENT),
         }
     }
@@ -388,12 +1205,12 @@ impl Filesystem for PinoqFs {
         &mut self,
         _req: &Request,
         ino: u64,
-        _mode: Option<u32>,
-        _uid: Option<u32>,
-        _gid: Option<u32>,
-        _size: Option<u64>,
-        _atime: Option<TimeOrNow>,
-        _mtime: Option<TimeOrNow>,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
         _ctime: Option<SystemTime>,
         _fh: Option<u64>,
         _crtime: Option<SystemTime>,
@@ -402,28 +1219,58 @@ impl Filesystem for PinoqFs {
         _flags: Option<u32>,
         reply: ReplyAttr,
     ) {
-        // TODO: not implemented
-        // just return the attrs for now to supress the warnings
-        let ino = self.convert_inode_index(ino);
-        match self.get_from_block::<INode>(ino as u32) {
-            Ok(node) => reply.attr(&TTL, &node.as_attr(ino as _)),
-            Err(_) => reply.error(libc::ENOENT),
+        let inode = self.convert_inode_index(ino);
+        if let Some(size) = size {
+            if let Err(e) = self.truncate(inode, size) {
+                reply.error(e.to_code());
+                return;
+            }
+        }
+
+        let time = |t| match t {
+            TimeOrNow::SpecificTime(t) => t,
+            TimeOrNow::Now => SystemTime::now(),
+        };
+        match self.set_attr(inode, mode, uid, gid, atime.map(time), mtime.map(time)) {
+            Ok(node) => reply.attr(&TTL, &node.as_attr(ino)),
+            Err(e) => reply.error(e.to_code()),
         }
     }
 
     fn create(
         &mut self,
-        _req: &Request,
+        req: &Request,
         parent: u64,
         name: &OsStr,
-        _mode: u32,
-        _umask: u32,
+        mode: u32,
+        umask: u32,
         _flags: i32,
         reply: ReplyCreate,
     ) {
         let parent = self.convert_inode_index(parent);
-        match self.create_entry(parent, name) {
-            Ok(attrs) => reply.created(&TTL, &attrs, 0, 0, 0),
+        let mode = libc::S_IFREG | (mode & !umask & 0o7777);
+        match self.create_entry(parent, name, mode, req.uid(), req.gid()) {
+            Ok(attrs) => {
+                let fh = self.fd_manager.open(self.convert_inode_index(attrs.ino));
+                reply.created(&TTL, &attrs, 0, fh, 0)
+            }
+            Err(e) => reply.error(e.to_code()),
+        }
+    }
+
+    fn mkdir(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        reply: ReplyEntry,
+    ) {
+        let parent = self.convert_inode_index(parent);
+        let mode = libc::S_IFDIR | (mode & !umask & 0o7777);
+        match self.create_entry(parent, name, mode, req.uid(), req.gid()) {
+            Ok(attrs) => reply.entry(&TTL, &attrs, 0),
             Err(e) => reply.error(e.to_code()),
         }
     }
@@ -433,7 +1280,7 @@ impl Filesystem for PinoqFs {
         _req: &Request,
         inode: u64,
         fh: u64,
-        _offset: i64,
+        offset: i64,
         data: &[u8],
         _write_flags: u32,
         _flags: i32,
@@ -441,8 +1288,7 @@ impl Filesystem for PinoqFs {
         reply: ReplyWrite,
     ) {
         let inode = self.convert_inode_index(inode);
-        // TODO: consider offset
-        match self.write(inode, fh, data) {
+        match self.write(inode, Some(fh), offset as _, data) {
             Ok(n) => reply.written(n as _),
             Err(e) => reply.error(e.to_code()),
         }
@@ -454,13 +1300,13 @@ impl Filesystem for PinoqFs {
         inode: u64,
         fh: u64,
         offset: i64,
-        _size: u32,
+        size: u32,
         _flags: i32,
         _lock_owner: Option<u64>,
         reply: ReplyData,
     ) {
         let inode = self.convert_inode_index(inode);
-        match self.read(inode, fh, offset as _) {
+        match self.read(inode, Some(fh), offset as _, size as _) {
             Ok(d) => {
                 reply.data(&d);
             }
@@ -470,11 +1316,58 @@ impl Filesystem for PinoqFs {
         }
     }
 
+    fn unlink(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let parent = self.convert_inode_index(parent);
+        match self.remove_entry(parent, name, false) {
+            Ok(_) => reply.ok(),
+            Err(e) => reply.error(e.to_code()),
+        }
+    }
+
+    fn rmdir(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let parent = self.convert_inode_index(parent);
+        match self.remove_entry(parent, name, true) {
+            Ok(_) => reply.ok(),
+            Err(e) => reply.error(e.to_code()),
+        }
+    }
+
+    fn rename(
+        &mut self,
+        _req: &Request,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        flags: u32,
+        reply: ReplyEmpty,
+    ) {
+        let parent = self.convert_inode_index(parent);
+        let newparent = self.convert_inode_index(newparent);
+        match self.rename_entry(parent, name, newparent, newname, flags) {
+            Ok(_) => reply.ok(),
+            Err(e) => reply.error(e.to_code()),
+        }
+    }
+
     fn open(&mut self, _req: &Request, inode: u64, _flags: i32, reply: ReplyOpen) {
         let inode = self.convert_inode_index(inode);
-        self.fd_manager
-            .insert(inode, FileDescriptor { next_block: None });
-        reply.opened(inode, fuser::consts::FOPEN_DIRECT_IO);
+        let fh = self.fd_manager.open(inode);
+        reply.opened(fh, fuser::consts::FOPEN_DIRECT_IO);
+    }
+
+    fn release(
+        &mut self,
+        _req: &Request,
+        _inode: u64,
+        fh: u64,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        self.fd_manager.release(fh);
+        reply.ok();
     }
 }
 
@@ -482,35 +1375,24 @@ impl Filesystem for PinoqFs {
 mod tests {
     use super::*;
     use crate::pinoq::config::*;
+    use crate::pinoq::filefmt::RawBlock;
+    use crate::pinoq::testing::*;
     use crate::pinoq::*;
-    use tempfile::tempdir;
+    use std::os::unix::ffi::OsStrExt;
 
     #[test]
     fn test_write_data_blocks() {
-        let dir = tempdir().unwrap();
-        let path = dir.path().join("my-volume.pnoq");
-        let path = path.to_str().unwrap();
-        let password = "testpass".to_string();
-
-        mkfs(2, 1024, path, "password").unwrap();
-
-        let config = Config {
-            disk: path.to_string(),
-            mount: "".to_string(),
-            current: Current {
-                aspect: 1,
-                password: password.clone(),
-            },
-        };
+        let (_dir, path) = test_volume(&["password", "password"], 1024);
+        let config = test_config(&path, 1, "password");
 
         let data = vec![69; BLOCK_SIZE];
         let mut fs = PinoqFs::new(config).unwrap();
         fs.init_root().unwrap();
 
-        fs.create_entry(0, OsStr::new("file.txt")).unwrap();
+        fs.create_entry(0, OsStr::new("file.txt"), libc::S_IFREG | 0o644, 0, 0)
+            .unwrap();
 
-        fs.fd_manager.insert(0, FileDescriptor { next_block: None });
-        fs.write(2, 0, &data).unwrap();
+        fs.write(2, None, 0, &data).unwrap();
 
         let b1 = fs.get_from_block::<Block>(3).unwrap();
         let b2 = fs.get_from_block::<Block>(4).unwrap();
@@ -519,4 +1401,795 @@ mod tests {
         assert_eq!(b1.data.len() + b2.data.len(), data.len());
         assert_eq!(b1.next_block, 4);
     }
+
+    #[test]
+    fn test_nested_directories() {
+        let (_dir, path) = test_volume(&["password", "password"], 1024);
+        let config = test_config(&path, 0, "password");
+
+        let mut fs = PinoqFs::new(config).unwrap();
+        let root = fs.aspect.root_block as u64;
+
+        let a = fs
+            .create_entry(root, OsStr::new("a"), libc::S_IFDIR | 0o755, 0, 0)
+            .unwrap();
+        let a = fs.convert_inode_index(a.ino);
+        let b = fs
+            .create_entry(a, OsStr::new("b"), libc::S_IFDIR | 0o755, 0, 0)
+            .unwrap();
+        let b = fs.convert_inode_index(b.ino);
+        fs.create_entry(b, OsStr::new("file.txt"), libc::S_IFREG | 0o644, 0, 0)
+            .unwrap();
+
+        let attr = fs.lookup_name(b, OsStr::new("file.txt")).unwrap();
+        assert_eq!(attr.kind, fuser::FileType::RegularFile);
+        assert!(fs
+            .create_entry(b, OsStr::new("file.txt"), libc::S_IFREG | 0o644, 0, 0)
+            .is_err());
+
+        let entries = fs.list_entries(b).unwrap();
+        assert_eq!(entries[0], (b, fuser::FileType::Directory, ".".to_string()));
+        assert_eq!(
+            entries[1],
+            (a, fuser::FileType::Directory, "..".to_string())
+        );
+        assert_eq!(entries[2].2, "file.txt");
+
+        let entries = fs.list_entries(a).unwrap();
+        assert_eq!(entries[1].0, root);
+        let entries = fs.list_entries(root).unwrap();
+        assert_eq!(entries[1].0, root);
+        assert_eq!(fs.to_fuse_inode(root as _), 1);
+    }
+
+    #[test]
+    fn test_full_directory() {
+        let (_dir, path) = test_volume(&["password"], 1024);
+        let config = test_config(&path, 0, "password");
+
+        let mut fs = PinoqFs::new(config).unwrap();
+        let root = fs.aspect.root_block as u64;
+
+        let mut created = 0;
+        let full = loop {
+            let name = format!("{:016}", created);
+            match fs.create_entry(root, OsStr::new(&name), libc::S_IFREG | 0o644, 0, 0) {
+                Ok(_) => created += 1,
+                Err(e) => break e,
+            }
+        };
+        assert!(matches!(full, PinoqError::DirectoryFull));
+        assert!(created >= 30);
+
+        // nothing is allocated for an entry that doesn't fit
+        let used = fs.aspect.block_map.count_ones();
+        let result = fs.create_entry(
+            root,
+            OsStr::new("directory-name!!"),
+            libc::S_IFDIR | 0o755,
+            0,
+            0,
+        );
+        assert!(matches!(result, Err(PinoqError::DirectoryFull)));
+        assert_eq!(fs.aspect.block_map.count_ones(), used);
+        assert_eq!(fs.list_entries(root).unwrap().len(), created + 2);
+
+        // nor moved into it
+        let name = format!("{:016}", 0);
+        let new_name = format!("{:064}", 0);
+        let result = fs.rename_entry(root, OsStr::new(&name), root, OsStr::new(&new_name), 0);
+        assert!(matches!(result, Err(PinoqError::DirectoryFull)));
+        assert_eq!(fs.list_entries(root).unwrap().len(), created + 2);
+    }
+
+    #[test]
+    fn test_remove_entries() {
+        let (_dir, path) = test_volume(&["password", "password"], 1024);
+        let config = test_config(&path, 0, "password");
+
+        let mut fs = PinoqFs::new(config).unwrap();
+        let root = fs.aspect.root_block as u64;
+        let used = fs.aspect.block_map.count_ones();
+
+        let d = fs
+            .create_entry(root, OsStr::new("dir"), libc::S_IFDIR | 0o755, 0, 0)
+            .unwrap();
+        let d = fs.convert_inode_index(d.ino);
+        let f = fs
+            .create_entry(d, OsStr::new("file"), libc::S_IFREG | 0o644, 0, 0)
+            .unwrap();
+        let f = fs.convert_inode_index(f.ino);
+        fs.write(f, None, 0, &vec![1; BLOCK_SIZE * 3]).unwrap();
+        assert!(fs.aspect.block_map.count_ones() > used + 4);
+
+        let err = fs.remove_entry(root, OsStr::new("dir"), true).unwrap_err();
+        assert!(matches!(err, PinoqError::NotEmpty));
+        let err = fs.remove_entry(d, OsStr::new("file"), true).unwrap_err();
+        assert!(matches!(err, PinoqError::NoDirectory));
+        let err = fs.remove_entry(root, OsStr::new("dir"), false).unwrap_err();
+        assert!(matches!(err, PinoqError::IsDirectory));
+        // names are stored as UTF-8
+        let invalid = OsStr::from_bytes(b"\xff");
+        let err = fs.remove_entry(root, invalid, false).unwrap_err();
+        assert!(matches!(err, PinoqError::InvalidArgument));
+        let err = fs.lookup_name(root, invalid).unwrap_err();
+        assert!(matches!(err, PinoqError::InvalidArgument));
+        let err = fs
+            .create_entry(root, invalid, libc::S_IFREG | 0o644, 0, 0)
+            .unwrap_err();
+        assert!(matches!(err, PinoqError::InvalidArgument));
+
+        fs.remove_entry(d, OsStr::new("file"), false).unwrap();
+        fs.remove_entry(root, OsStr::new("dir"), true).unwrap();
+        assert_eq!(fs.aspect.block_map.count_ones(), used);
+        assert_eq!(fs.block_map.count_ones(), used);
+        assert!(fs.list_entries(root).unwrap().len() == 2);
+
+        // the reclaimed blocks must be persisted as well
+        let cursor = Cursor::new(&fs.mmap);
+        let (aspect, _) =
+            decrypt_aspect(cursor, &fs.geometry, 0, test_options().kdf, b"password").unwrap();
+        assert_eq!(aspect.block_map, fs.aspect.block_map);
+    }
+
+    #[test]
+    fn test_random_access() {
+        let (_dir, path) = test_volume(&["password", "password"], 1024);
+        let config = test_config(&path, 0, "password");
+
+        let mut fs = PinoqFs::new(config).unwrap();
+        let root = fs.aspect.root_block as u64;
+        let f = fs
+            .create_entry(root, OsStr::new("f"), libc::S_IFREG | 0o644, 0, 0)
+            .unwrap();
+        let f = fs.convert_inode_index(f.ino);
+
+        let mut expected = vec![1u8; RAW_BLK_SIZE * 2 + 10];
+        fs.write(f, None, 0, &expected).unwrap();
+
+        // overwrite across a block boundary
+        let patch = vec![2u8; 100];
+        fs.write(f, None, (RAW_BLK_SIZE - 50) as _, &patch).unwrap();
+        expected[RAW_BLK_SIZE - 50..RAW_BLK_SIZE + 50].copy_from_slice(&patch);
+
+        // append
+        fs.write(f, None, expected.len() as _, &[3u8; 20]).unwrap();
+        expected.extend_from_slice(&[3u8; 20]);
+
+        let data = fs.read(f, None, 0, usize::MAX).unwrap();
+        assert_eq!(data, expected);
+        let data = fs.read(f, None, (RAW_BLK_SIZE - 60) as _, 30).unwrap();
+        assert_eq!(data, expected[RAW_BLK_SIZE - 60..RAW_BLK_SIZE - 30]);
+        let data = fs.read(f, None, expected.len() as u64 - 5, 4096).unwrap();
+        assert_eq!(data, [3u8; 5]);
+        assert!(fs.read(f, None, 1 << 20, 4096).unwrap().is_empty());
+
+        // writing past the end of file leaves a hole of zeros
+        let g = fs
+            .create_entry(root, OsStr::new("g"), libc::S_IFREG | 0o644, 0, 0)
+            .unwrap();
+        let g = fs.convert_inode_index(g.ino);
+        let offset = RAW_BLK_SIZE * 2 + 7;
+        fs.write(g, None, offset as _, &[4u8; 3]).unwrap();
+        let data = fs.read(g, None, 0, usize::MAX).unwrap();
+        assert_eq!(data.len(), offset + 3);
+        assert!(data[..offset].iter().all(|&x| x == 0));
+        assert_eq!(data[offset..], [4u8; 3]);
+    }
+
+    #[test]
+    fn test_file_cursor() {
+        let (_dir, path) = test_volume(&["password"], 1024);
+        let config = test_config(&path, 0, "password");
+
+        let mut fs = PinoqFs::new(config).unwrap();
+        let root = fs.aspect.root_block as u64;
+        let f = fs
+            .create_entry(root, OsStr::new("f"), libc::S_IFREG | 0o644, 0, 0)
+            .unwrap();
+        let f = fs.convert_inode_index(f.ino);
+        let fh = fs.fd_manager.open(f);
+
+        // sequential writes pick up where the previous one stopped
+        let expected: Vec<u8> = (0..RAW_BLK_SIZE * 5).map(|i| (i % 251) as u8).collect();
+        for (i, chunk) in expected.chunks(300).enumerate() {
+            fs.write(f, Some(fh), (i * 300) as _, chunk).unwrap();
+        }
+        let (index, n) = fs.fd_manager.cursor(Some(fh), f, usize::MAX).unwrap();
+        assert_eq!(index, 4);
+        assert_eq!(
+            fs.get_from_block::<Block>(n).unwrap().next_block,
+            0xFFFFFFFF
+        );
+
+        // a cursor past the offset is ignored
+        let data = fs.read(f, Some(fh), 10, 20).unwrap();
+        assert_eq!(data, expected[10..30]);
+        let first = fs.get_from_block::<INode>(f as _).unwrap().data_block;
+        assert_eq!(fs.fd_manager.cursor(Some(fh), f, 0), Some((0, first)));
+        let data = fs.read(f, Some(fh), RAW_BLK_SIZE as u64 * 3, 4096).unwrap();
+        assert_eq!(data, expected[RAW_BLK_SIZE * 3..]);
+
+        // freed blocks aren't followed
+        fs.truncate(f, 10).unwrap();
+        assert!(fs.fd_manager.cursor(Some(fh), f, usize::MAX).is_none());
+        fs.write(f, Some(fh), RAW_BLK_SIZE as u64 * 2, &[7; 5])
+            .unwrap();
+        let data = fs.read(f, Some(fh), 0, usize::MAX).unwrap();
+        assert_eq!(data.len(), RAW_BLK_SIZE * 2 + 5);
+        assert_eq!(data[..10], expected[..10]);
+        assert!(data[10..RAW_BLK_SIZE * 2].iter().all(|&x| x == 0));
+
+        fs.fd_manager.release(fh);
+        assert!(fs.fd_manager.cursor(Some(fh), f, usize::MAX).is_none());
+    }
+
+    #[test]
+    fn test_write_in_place() {
+        let (_dir, path) = test_volume(&["password"], 1024);
+        let config = test_config(&path, 0, "password");
+
+        let mut fs = PinoqFs::new(config).unwrap();
+        let root = fs.aspect.root_block as u64;
+        let f = fs
+            .create_entry(root, OsStr::new("f"), libc::S_IFREG | 0o644, 0, 0)
+            .unwrap();
+        let f = fs.convert_inode_index(f.ino);
+        fs.write(f, None, 0, &vec![1; RAW_BLK_SIZE * 6]).unwrap();
+
+        let mut chain = vec![fs.get_from_block::<INode>(f as _).unwrap().data_block];
+        while chain.len() < 6 {
+            let last = *chain.last().unwrap();
+            chain.push(fs.get_from_block::<Block>(last).unwrap().next_block);
+        }
+        let raw = |fs: &PinoqFs, n: u32| {
+            let offset = fs.get_block_offset(n);
+            fs.mmap[offset..offset + BLOCK_SIZE].to_vec()
+        };
+        let before: Vec<_> = chain.iter().map(|&n| raw(&fs, n)).collect();
+        let slot = |fs: &PinoqFs| {
+            let offset = fs.geometry.aspect_offset(0);
+            fs.mmap[offset..fs.geometry.aspect_offset(1)].to_vec()
+        };
+        let aspect = slot(&fs);
+
+        // a write in the middle, without any cursor to start from
+        fs.write(f, None, (RAW_BLK_SIZE * 5 + 10) as _, &[2; 2])
+            .unwrap();
+        for (i, &n) in chain.iter().enumerate() {
+            assert_eq!(raw(&fs, n) == before[i], i != 5);
+        }
+        // no block was allocated, the aspect is left as it was
+        assert_eq!(slot(&fs), aspect);
+        let data = fs.read(f, None, 0, usize::MAX).unwrap();
+        assert_eq!(data[RAW_BLK_SIZE * 5 + 10..RAW_BLK_SIZE * 5 + 12], [2, 2]);
+    }
+
+    #[test]
+    fn test_truncate() {
+        let (_dir, path) = test_volume(&["password", "password"], 1024);
+        let config = test_config(&path, 0, "password");
+
+        let mut fs = PinoqFs::new(config).unwrap();
+        let root = fs.aspect.root_block as u64;
+        let f = fs
+            .create_entry(root, OsStr::new("f"), libc::S_IFREG | 0o644, 0, 0)
+            .unwrap();
+        let f = fs.convert_inode_index(f.ino);
+        let used = fs.aspect.block_map.count_ones();
+
+        fs.write(f, None, 0, &vec![1u8; RAW_BLK_SIZE * 3]).unwrap();
+        fs.write(f, None, 10, &[2u8; 10]).unwrap();
+        let node = fs.get_from_block::<INode>(f as _).unwrap();
+        assert_eq!(node.size, RAW_BLK_SIZE * 3);
+        assert_eq!(fs.aspect.block_map.count_ones(), used + 3);
+
+        fs.truncate(f, (RAW_BLK_SIZE + 5) as _).unwrap();
+        assert_eq!(fs.aspect.block_map.count_ones(), used + 2);
+        let data = fs.read(f, None, 0, usize::MAX).unwrap();
+        assert_eq!(data.len(), RAW_BLK_SIZE + 5);
+        assert_eq!(data[10..20], [2u8; 10]);
+
+        fs.truncate(f, (RAW_BLK_SIZE * 2) as _).unwrap();
+        let data = fs.read(f, None, 0, usize::MAX).unwrap();
+        assert_eq!(data.len(), RAW_BLK_SIZE * 2);
+        assert!(data[RAW_BLK_SIZE + 5..].iter().all(|&x| x == 0));
+
+        fs.truncate(f, 0).unwrap();
+        assert_eq!(fs.aspect.block_map.count_ones(), used);
+        let node = fs.get_from_block::<INode>(f as _).unwrap();
+        assert_eq!(node.size, 0);
+        assert_eq!(node.data_block, 0xFFFFFFFF);
+        assert!(fs.read(f, None, 0, usize::MAX).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_rename() {
+        let (_dir, path) = test_volume(&["password", "password"], 1024);
+        let config = test_config(&path, 0, "password");
+
+        let mut fs = PinoqFs::new(config).unwrap();
+        let root = fs.aspect.root_block as u64;
+        let a = fs
+            .create_entry(root, OsStr::new("a"), libc::S_IFDIR | 0o755, 0, 0)
+            .unwrap();
+        let a = fs.convert_inode_index(a.ino);
+        let b = fs
+            .create_entry(root, OsStr::new("b"), libc::S_IFDIR | 0o755, 0, 0)
+            .unwrap();
+        let b = fs.convert_inode_index(b.ino);
+        let f = fs
+            .create_entry(a, OsStr::new("f"), libc::S_IFREG | 0o644, 0, 0)
+            .unwrap();
+        let f = fs.convert_inode_index(f.ino);
+        let g = fs
+            .create_entry(b, OsStr::new("g"), libc::S_IFREG | 0o644, 0, 0)
+            .unwrap();
+        let g = fs.convert_inode_index(g.ino);
+        fs.write(g, None, 0, &[1u8; 10]).unwrap();
+        let used = fs.aspect.block_map.count_ones();
+
+        // move within the same directory
+        fs.rename_entry(a, OsStr::new("f"), a, OsStr::new("f2"), 0)
+            .unwrap();
+        assert!(fs.lookup_name(a, OsStr::new("f")).is_err());
+        assert!(fs.lookup_name(a, OsStr::new("f2")).is_ok());
+
+        // replace an existing file in another directory
+        let err = fs
+            .rename_entry(
+                a,
+                OsStr::new("f2"),
+                b,
+                OsStr::new("g"),
+                libc::RENAME_NOREPLACE,
+            )
+            .unwrap_err();
+        assert!(matches!(err, PinoqError::AlreadyExists));
+        fs.rename_entry(a, OsStr::new("f2"), b, OsStr::new("g"), 0)
+            .unwrap();
+        let attr = fs.lookup_name(b, OsStr::new("g")).unwrap();
+        assert_eq!(fs.convert_inode_index(attr.ino), f);
+        assert_eq!(fs.aspect.block_map.count_ones(), used - 2);
+
+        // move a directory and fix its parent
+        fs.rename_entry(root, OsStr::new("b"), a, OsStr::new("b"), 0)
+            .unwrap();
+        let entries = fs.list_entries(b).unwrap();
+        assert_eq!(entries[1].0, a);
+        let err = fs
+            .rename_entry(root, OsStr::new("a"), b, OsStr::new("a"), 0)
+            .unwrap_err();
+        assert!(matches!(err, PinoqError::InvalidArgument));
+
+        // exchange a file and a directory
+        fs.rename_entry(
+            b,
+            OsStr::new("g"),
+            root,
+            OsStr::new("a"),
+            libc::RENAME_EXCHANGE,
+        )
+        .unwrap_err();
+        let c = fs
+            .create_entry(root, OsStr::new("c"), libc::S_IFREG | 0o644, 0, 0)
+            .unwrap();
+        let c = fs.convert_inode_index(c.ino);
+        fs.rename_entry(
+            b,
+            OsStr::new("g"),
+            root,
+            OsStr::new("c"),
+            libc::RENAME_EXCHANGE,
+        )
+        .unwrap();
+        let attr = fs.lookup_name(root, OsStr::new("c")).unwrap();
+        assert_eq!(fs.convert_inode_index(attr.ino), f);
+        let attr = fs.lookup_name(b, OsStr::new("g")).unwrap();
+        assert_eq!(fs.convert_inode_index(attr.ino), c);
+
+        let invalid = OsStr::from_bytes(b"\xff");
+        let err = fs
+            .rename_entry(root, OsStr::new("c"), root, invalid, 0)
+            .unwrap_err();
+        assert!(matches!(err, PinoqError::InvalidArgument));
+    }
+
+    #[test]
+    fn test_attributes() {
+        let (_dir, path) = test_volume(&["password", "password"], 1024);
+        let config = test_config(&path, 0, "password");
+
+        let mut fs = PinoqFs::new(config).unwrap();
+        let root = fs.aspect.root_block as u64;
+        let f = fs
+            .create_entry(root, OsStr::new("f"), libc::S_IFREG | 0o600, 1000, 100)
+            .unwrap();
+        assert_eq!(f.perm, 0o600);
+        assert_eq!((f.uid, f.gid), (1000, 100));
+        assert_ne!(f.mtime, UNIX_EPOCH);
+        let f = fs.convert_inode_index(f.ino);
+
+        let mtime = UNIX_EPOCH + Duration::from_secs(1_000_000);
+        fs.set_attr(f, Some(0o4755), Some(0), None, None, Some(mtime))
+            .unwrap();
+        let node = fs.get_from_block::<INode>(f as _).unwrap();
+        assert_eq!(node.perm(), 0o4755);
+        assert!(!node.is_dir());
+        assert_eq!((node.uid, node.gid), (0, 100));
+        assert_eq!(node.times.mtime, mtime);
+
+        // nothing but the epoch is stored when timestamps are hidden
+        fs.config.hide_timestamps = true;
+        fs.write(f, None, 0, &[1u8; 10]).unwrap();
+        let g = fs
+            .create_entry(root, OsStr::new("g"), libc::S_IFREG | 0o644, 0, 0)
+            .unwrap();
+        assert_eq!(g.mtime, UNIX_EPOCH);
+        assert_eq!(g.ctime, UNIX_EPOCH);
+        let node = fs.get_from_block::<INode>(f as _).unwrap();
+        assert_eq!(node.times.mtime, UNIX_EPOCH);
+    }
+
+    #[test]
+    fn test_aspect_passwords() {
+        let (_dir, path) = test_volume(&["first", "second"], 1024);
+
+        let config = |aspect: u32, password: &str| test_config(&path, aspect, password);
+
+        let mut fs = PinoqFs::new(config(0, "first")).unwrap();
+        let root = fs.aspect.root_block as u64;
+        fs.create_entry(root, OsStr::new("f"), libc::S_IFREG | 0o600, 0, 0)
+            .unwrap();
+        // nothing but the own blocks is known
+        assert_eq!(fs.block_map, fs.aspect.block_map);
+
+        // the volume is locked while it's open
+        let result = PinoqFs::new(config(1, "second"));
+        assert!(matches!(result, Err(PinoqError::Busy)));
+        drop(fs);
+
+        let result = PinoqFs::new(config(0, "second"));
+        assert!(matches!(result, Err(PinoqError::WrongPassword)));
+        let result = PinoqFs::new(config(1, "first"));
+        assert!(matches!(result, Err(PinoqError::WrongPassword)));
+
+        let fs = PinoqFs::new(config(1, "second")).unwrap();
+        let root = fs.aspect.root_block;
+        assert_eq!(fs.list_entries(root as _).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_corrupted_volume() {
+        let (_dir, path) = test_volume(&["password"], 64);
+        let volume = std::fs::read(&path).unwrap();
+
+        let config = || test_config(&path, 0, "password");
+        assert!(PinoqFs::new(config()).is_ok());
+
+        std::fs::write(&path, &volume[..BLOCK_SIZE]).unwrap();
+        let result = PinoqFs::new(config());
+        assert!(matches!(result, Err(PinoqError::Truncated)));
+
+        std::fs::write(&path, &volume[..4]).unwrap();
+        let result = PinoqFs::new(config());
+        assert!(matches!(result, Err(PinoqError::Truncated)));
+
+        std::fs::write(&path, []).unwrap();
+        let result = PinoqFs::new(config());
+        assert!(matches!(result, Err(PinoqError::Truncated)));
+
+        let mut garbage = volume.clone();
+        garbage[0] ^= 1;
+        std::fs::write(&path, &garbage).unwrap();
+        let result = PinoqFs::new(config());
+        assert!(matches!(result, Err(PinoqError::BadMagic)));
+
+        // the version follows the magic
+        let mut newer = volume;
+        newer[4] ^= 1;
+        std::fs::write(&path, &newer).unwrap();
+        let result = PinoqFs::new(config());
+        assert!(matches!(result, Err(PinoqError::UnsupportedVersion)));
+
+        // so do KDF parameters that can't be used, the cost follows the algorithm
+        newer[4] ^= 1;
+        newer[12..16].copy_from_slice(&0u32.to_le_bytes());
+        std::fs::write(&path, &newer).unwrap();
+        let result = PinoqFs::new(config());
+        assert!(matches!(result, Err(PinoqError::UnsupportedVersion)));
+    }
+
+    #[test]
+    fn test_protected_aspects() {
+        let (_dir, path) = test_volume(&["first", "second"], 1024);
+
+        let config = |aspect: u32, password: &str, protect: Vec<Current>| Config {
+            protect,
+            ..test_config(&path, aspect, password)
+        };
+
+        let data = vec![7; BLOCK_SIZE * 4];
+        let mut fs = PinoqFs::new(config(0, "first", vec![])).unwrap();
+        let root = fs.aspect.root_block as u64;
+        let f = fs
+            .create_entry(root, OsStr::new("f"), libc::S_IFREG | 0o600, 0, 0)
+            .unwrap();
+        let f = fs.convert_inode_index(f.ino);
+        fs.write(f, None, 0, &data).unwrap();
+        let first = fs.aspect.block_map.clone();
+        drop(fs);
+
+        let protect = vec![test_current(0, "first")];
+        let mut fs = PinoqFs::new(config(1, "second", protect)).unwrap();
+        // the protected blocks along with the new root inode and directory
+        assert_eq!(fs.block_map.count_ones(), first.count_ones() + 2);
+        let root = fs.aspect.root_block as u64;
+        let g = fs
+            .create_entry(root, OsStr::new("g"), libc::S_IFREG | 0o600, 0, 0)
+            .unwrap();
+        let g = fs.convert_inode_index(g.ino);
+        fs.write(g, None, 0, &vec![9; BLOCK_SIZE * 4]).unwrap();
+        assert!(!(fs.aspect.block_map.clone() & first).any());
+        drop(fs);
+
+        // the data of the protected aspect is intact
+        let mut fs = PinoqFs::new(config(0, "first", vec![])).unwrap();
+        assert_eq!(fs.read(f, None, 0, data.len() as _).unwrap(), data);
+        drop(fs);
+
+        // protecting requires the right password
+        let protect = vec![test_current(0, "second")];
+        let result = PinoqFs::new(config(1, "second", protect));
+        assert!(matches!(result, Err(PinoqError::WrongPassword)));
+    }
+
+    #[test]
+    fn test_shared_password() {
+        let (_dir, path) = test_volume(&["password", "password", "other"], 1024);
+        let config = |aspect: u32| test_config(&path, aspect, "password");
+
+        let first = PinoqFs::new(config(1)).unwrap().aspect;
+        // the blocks of an aspect opened by the same password are never reused
+        let fs = PinoqFs::new(config(0)).unwrap();
+        assert!(!(fs.aspect.block_map.clone() & &first.block_map).any());
+        assert_eq!(
+            fs.block_map.count_ones(),
+            fs.aspect.block_map.count_ones() + first.block_map.count_ones()
+        );
+    }
+
+    #[test]
+    fn test_random_allocation() {
+        let options = MkfsOptions {
+            allocation: Allocation::Random,
+            ..test_options()
+        };
+        let (_dir, path) = test_volume_with(&["password"], Size::Blocks(256), &options);
+
+        let config = test_config(&path, 0, "password");
+
+        let mut fs = PinoqFs::new(config).unwrap();
+        let used = fs.block_map.count_ones();
+        let mut allocated = vec![];
+        while let Ok(n) = fs.allocate_block() {
+            allocated.push(n);
+        }
+        // every free block is handed out exactly once
+        assert_eq!(allocated.len(), 256 - used);
+        assert!(fs.block_map.all());
+        // but not in order
+        assert!(!allocated.windows(2).all(|w| w[0] < w[1]));
+    }
+
+    #[test]
+    fn test_random_allocation_speed() {
+        let options = MkfsOptions {
+            allocation: Allocation::Random,
+            ..test_options()
+        };
+        let (_dir, path) = test_volume_with(&["password"], Size::Blocks(256), &options);
+
+        let config = test_config(&path, 0, "password");
+
+        let mut fs = PinoqFs::new(config).unwrap();
+        // the maps of a 1 GiB volume, filling it must not take time quadratic in its size
+        let blocks = 1 << 20;
+        fs.block_map = BitVec::repeat(false, blocks);
+        fs.aspect.block_map = BitVec::repeat(false, blocks);
+        let start = std::time::Instant::now();
+        let mut allocated = 0;
+        while fs.allocate_block().is_ok() {
+            allocated += 1;
+        }
+        assert_eq!(allocated, blocks);
+        assert!(fs.block_map.all());
+        assert!(start.elapsed() < Duration::from_secs(30));
+    }
+
+    #[test]
+    fn test_rekey() {
+        let (_dir, path) = test_volume(&["password"], 256);
+
+        let config = || test_config(&path, 0, "password");
+
+        let data = vec![3; BLOCK_SIZE * 3];
+        let mut fs = PinoqFs::new(config()).unwrap();
+        let root = fs.aspect.root_block as u64;
+        let f = fs
+            .create_entry(root, OsStr::new("f"), libc::S_IFREG | 0o600, 0, 0)
+            .unwrap();
+        let f = fs.convert_inode_index(f.ino);
+        fs.write(f, None, 0, &data).unwrap();
+        let key = fs.aspect.key.clone();
+        drop(fs);
+
+        rekey(&config()).unwrap();
+        let mut fs = PinoqFs::new(config()).unwrap();
+        assert_ne!(fs.aspect.key.0, key.0);
+        assert_eq!(fs.read(f, None, 0, data.len() as _).unwrap(), data);
+
+        // interrupted after rewriting the first block
+        let key = fs.aspect.key.clone();
+        let next_key = crate::pinoq::encryption::random_key();
+        let first = fs.aspect.block_map.first_one().unwrap() as u32;
+        let raw = fs.get_from_block::<RawBlock>(first).unwrap();
+        fs.aspect.next_key = Some(next_key.clone());
+        fs.store_aspect().unwrap();
+        fs.aspect.key = next_key.clone();
+        fs.store_to_block(&raw, first).unwrap();
+        fs.aspect.key = key;
+        drop(fs);
+
+        let result = PinoqFs::new(config());
+        assert!(matches!(result, Err(PinoqError::RekeyPending)));
+
+        rekey(&config()).unwrap();
+        let mut fs = PinoqFs::new(config()).unwrap();
+        assert_eq!(fs.aspect.key.0, next_key.0);
+        assert!(fs.aspect.next_key.is_none());
+        assert_eq!(fs.read(f, None, 0, data.len() as _).unwrap(), data);
+    }
+
+    #[test]
+    fn test_check() {
+        let (_dir, path) = test_volume(&["first", "second"], 256);
+
+        let config = |aspect: u32, password: &str, protect: Vec<Current>| Config {
+            protect,
+            ..test_config(&path, aspect, password)
+        };
+        drop(PinoqFs::new(config(1, "second", vec![])).unwrap());
+        let protect = vec![test_current(1, "second")];
+
+        let mut fs = PinoqFs::new(config(0, "first", protect)).unwrap();
+        let root = fs.aspect.root_block as u64;
+        let d = fs
+            .create_entry(root, OsStr::new("d"), libc::S_IFDIR | 0o755, 0, 0)
+            .unwrap();
+        let d = fs.convert_inode_index(d.ino);
+        let f = fs
+            .create_entry(d, OsStr::new("f"), libc::S_IFREG | 0o644, 0, 0)
+            .unwrap();
+        let f = fs.convert_inode_index(f.ino);
+        fs.write(f, None, 0, &vec![1u8; RAW_BLK_SIZE * 3]).unwrap();
+        let g = fs
+            .create_entry(root, OsStr::new("g"), libc::S_IFREG | 0o644, 0, 0)
+            .unwrap();
+        let g = fs.convert_inode_index(g.ino) as u32;
+        assert_eq!(fs.check(false).unwrap(), vec![]);
+
+        // the second block of `f` is overwritten
+        let first = fs.get_from_block::<INode>(f as _).unwrap().data_block;
+        let second = fs.get_from_block::<Block>(first).unwrap().next_block;
+        let third = fs.get_from_block::<Block>(second).unwrap().next_block;
+        let offset = fs.geometry.block_offset(second);
+        fs.mmap[offset..offset + 16].fill(0);
+        // an entry to a free block, an unreachable block and a reachable one marked as free
+        let ghost = fs.find_free_block().unwrap() as u32;
+        let node = fs.get_from_block::<INode>(root as _).unwrap();
+        let mut content = fs.get_from_block::<Dir>(node.data_block).unwrap();
+        content.entries.insert("ghost".to_string(), ghost);
+        fs.store_to_block(&content, node.data_block).unwrap();
+        let leaked = fs.allocate_block().unwrap() as u32;
+        fs.aspect.block_map.set(g as _, false);
+        // a block of the protected aspect
+        let shared = fs.protected[0].1.first_one().unwrap() as u32;
+        fs.aspect.block_map.set(shared as _, true);
+
+        let problems = fs.check(false).unwrap();
+        let expected = [
+            Problem::DanglingEntry {
+                dir: root as _,
+                name: "ghost".to_string(),
+            },
+            Problem::BrokenChain {
+                inode: f as _,
+                block: second,
+            },
+            Problem::UnmarkedBlock(g),
+            Problem::LeakedBlock(second),
+            Problem::LeakedBlock(third),
+            Problem::LeakedBlock(leaked),
+            Problem::LeakedBlock(shared),
+            Problem::SharedBlock {
+                block: shared,
+                aspect: 1,
+            },
+        ];
+        assert_eq!(problems.len(), expected.len());
+        assert!(expected.iter().all(|p| problems.contains(p)));
+        assert!(problems.iter().all(|p| p.is_repairable(&problems)));
+        // unless it's leaked, a shared block is left to the protected aspect
+        let alone = Problem::SharedBlock {
+            block: shared,
+            aspect: 1,
+        };
+        assert!(!alone.is_repairable(&[]));
+
+        fs.check(true).unwrap();
+        assert_eq!(fs.check(false).unwrap(), vec![]);
+        assert_eq!(
+            fs.read(f, None, 0, RAW_BLK_SIZE * 3).unwrap(),
+            vec![1u8; RAW_BLK_SIZE]
+        );
+        let result = fs.lookup_name(root, OsStr::new("ghost"));
+        assert!(matches!(result, Err(PinoqError::NoEntry)));
+
+        // losing the root loses everything under it
+        fs.store_to_block(&RawBlock(vec![0; 8]), root as _).unwrap();
+        let problems = fs.check(false).unwrap();
+        assert!(problems.contains(&Problem::MissingRoot));
+        fs.check(true).unwrap();
+        assert_eq!(fs.check(false).unwrap(), vec![]);
+        let root = fs.aspect.root_block;
+        assert_eq!(fs.list_entries(root as _).unwrap().len(), 2);
+        assert_eq!(fs.aspect.block_map.count_ones(), 2);
+    }
+
+    #[test]
+    fn test_paths() {
+        let (_dir, path) = test_volume(&["password"], 256);
+
+        let config = test_config(&path, 0, "password");
+        let mut fs = PinoqFs::new(config).unwrap();
+        let used = fs.aspect.block_map.count_ones();
+
+        fs.create_dir("/a", 0o755).unwrap();
+        fs.create_dir("a/b/", 0o700).unwrap();
+        let data = vec![7u8; RAW_BLK_SIZE * 2 + 5];
+        fs.write_file("/a/b/f", 0o600, &data[..]).unwrap();
+        fs.write_file("/a/g", 0o644, &b"old content"[..]).unwrap();
+        fs.write_file("/a/./g", 0o644, &b"new"[..]).unwrap();
+
+        let entries = fs.read_dir("/a").unwrap();
+        let names: Vec<_> = entries.iter().map(|(n, _)| n.as_str()).collect();
+        assert_eq!(names, ["b", "g"]);
+        assert!(entries[0].1.is_dir());
+        assert_eq!(fs.metadata("/a/b/f").unwrap().perm(), 0o600);
+        assert_eq!(fs.resolve("/a/b/..").unwrap(), fs.resolve("a").unwrap());
+
+        let mut out = vec![];
+        fs.read_file("/a/b/f", &mut out).unwrap();
+        assert_eq!(out, data);
+        let mut out = vec![];
+        fs.read_file("/a/g", &mut out).unwrap();
+        assert_eq!(out, b"new");
+
+        let result = fs.read_file("/a", vec![]);
+        assert!(matches!(result, Err(PinoqError::IsDirectory)));
+        let result = fs.write_file("/a/missing/f", 0o644, &b""[..]);
+        assert!(matches!(result, Err(PinoqError::NoEntry)));
+        let result = fs.write_file("/a/b", 0o644, &b""[..]);
+        assert!(matches!(result, Err(PinoqError::IsDirectory)));
+        assert!(matches!(
+            fs.remove("/", true),
+            Err(PinoqError::InvalidArgument)
+        ));
+
+        assert!(matches!(fs.remove("/a", false), Err(PinoqError::NotEmpty)));
+        fs.remove("/a", true).unwrap();
+        assert!(fs.read_dir("/").unwrap().is_empty());
+        assert_eq!(fs.aspect.block_map.count_ones(), used);
+    }
 }
@@ -0,0 +1,323 @@
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::pinoq::{
+    encryption::{
+        decrypt, encrypt, random_key, zeroize, Kdf, Key, Tag, WrappingKey, IV, IV_LEN, KEY_LEN,
+        SALT_LEN, TAG_LEN,
+    },
+    error::{PinoqError, Result},
+    filefmt::{EncryptedAspect, BLOCK_SIZE},
+    Geometry,
+};
+
+use serde::{Deserialize, Serialize};
+
+// the most blocks a journal holds, larger rewrites are split in batches
+pub(crate) const MAX_BLOCKS: usize = 256;
+// salt + iv + tag + the encrypted key of the journal
+const SEAL_LEN: usize = SALT_LEN + IV_LEN + TAG_LEN + KEY_LEN;
+
+/// the previous content of some regions of the volume, kept in a directory of its own
+/// while they're being rewritten in place
+/// it's encrypted with a key of its own, sealed under both the wrapping key the aspect
+/// was unlocked with and the one it's rewritten with, so either secret recovers it.
+/// it's padded to a size that only depends on the geometry, so it tells neither
+/// which aspect nor which regions. every journal has a random name, so the ones other
+/// aspects left are never in the way
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Journal {
+    pub regions: Vec<Region>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Region {
+    pub offset: u64,
+    pub data: Vec<u8>,
+    // of the new content, a region holding it was fully rewritten
+    pub digest: [u8; 32],
+    #[serde(skip)]
+    new: Vec<u8>,
+}
+
+impl Journal {
+    /// the directory of the journals of `disk` when the config doesn't name one
+    pub fn default_dir(disk: &str) -> PathBuf {
+        PathBuf::from(format!("{}.journal", disk))
+    }
+
+    /// saves the bytes of `disk` at `offset` that `new` replaces once applied
+    pub fn save(&mut self, disk: &mut File, offset: u64, new: Vec<u8>) -> Result<()> {
+        let mut data = vec![0; new.len()];
+        disk.seek(SeekFrom::Start(offset))?;
+        disk.read_exact(&mut data)?;
+        let digest = openssl::sha::sha256(&new);
+        self.regions.push(Region {
+            offset,
+            data,
+            digest,
+            new,
+        });
+        Ok(())
+    }
+
+    /// the size of the encrypted part of every journal of a volume, it holds either
+    /// an aspect slot or `MAX_BLOCKS` blocks, each region with its offset, length and digest
+    pub fn padded_len(geometry: &Geometry) -> usize {
+        let slot = EncryptedAspect::size_of(geometry.blocks) + 48;
+        let blocks = MAX_BLOCKS * (BLOCK_SIZE + 48);
+        slot.max(blocks) + 8
+    }
+
+    /// the journal only appears in `dir` once it's complete, a crash while writing it
+    /// leaves nothing but a temporary file behind. returns where it's stored
+    /// `unlocked` and `wk` are the wrapping keys of the aspect before and after the rewrite
+    pub fn commit(
+        &self,
+        dir: &Path,
+        geometry: &Geometry,
+        unlocked: &WrappingKey,
+        wk: &WrappingKey,
+    ) -> Result<PathBuf> {
+        if !dir.exists() {
+            fs::create_dir_all(dir)?;
+            sync_parent(dir)?;
+        }
+        let path = dir.join(format!("{:016x}", rand::random::<u64>()));
+        let tmp = path.with_extension("tmp");
+
+        let mut data = bincode::serialize(self)?;
+        let len = Self::padded_len(geometry);
+        if data.len() > len {
+            return Err(PinoqError::InvalidArgument);
+        }
+        data.resize(len, 0);
+        let key = random_key();
+        let iv = IV::random();
+        let (data, tag) = encrypt(&data, &key, &iv, b"journal")?;
+
+        let mut file = File::create(&tmp)?;
+        for sealing in [unlocked, wk] {
+            let iv = IV::random();
+            let (sealed, tag) = encrypt(&key.0[..], &sealing.key, &iv, b"journal key")?;
+            file.write_all(&sealing.salt)?;
+            file.write_all(&iv.0)?;
+            file.write_all(&tag.0)?;
+            file.write_all(&sealed)?;
+        }
+        file.write_all(&iv.0)?;
+        file.write_all(&tag.0)?;
+        file.write_all(&data)?;
+        file.sync_all()?;
+        fs::rename(&tmp, &path)?;
+        sync_parent(&path)?;
+        Ok(path)
+    }
+
+    /// writes the new content of the regions, to call once committed
+    pub fn apply(&self, disk: &mut File) -> Result<()> {
+        for region in &self.regions {
+            disk.seek(SeekFrom::Start(region.offset))?;
+            disk.write_all(&region.new)?;
+        }
+        disk.sync_all().map_err(|e| e.into())
+    }
+
+    /// called once the regions are fully rewritten, `path` is the one `commit` returned
+    pub fn remove(path: &Path) -> Result<()> {
+        fs::remove_file(path)?;
+        sync_parent(path)
+    }
+
+    /// finishes the interrupted rewrites of `file` journaled in `dir`: a journal is
+    /// dropped if every region already holds its new content, otherwise the previous
+    /// content is put back. the journals that don't open with `secret` are left alone,
+    /// returns whether there was one that did
+    pub fn recover(dir: &Path, file: &mut File, secret: &[u8], kdf: Kdf) -> Result<bool> {
+        if !dir.exists() {
+            return Ok(false);
+        }
+
+        let mut recovered = false;
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            // never complete, whichever aspect left it
+            if path.extension().is_some_and(|e| e == "tmp") {
+                fs::remove_file(&path)?;
+                continue;
+            }
+            if !path.is_file() {
+                continue;
+            }
+            recovered |= Self::recover_one(&path, file, secret, kdf)?;
+        }
+        Ok(recovered)
+    }
+
+    fn recover_one(path: &Path, file: &mut File, secret: &[u8], kdf: Kdf) -> Result<bool> {
+        let content = fs::read(path)?;
+        // a stray file, or a journal cut short, whoever it belongs to can't use it
+        if content.len() < 2 * SEAL_LEN + IV_LEN + TAG_LEN {
+            log::warn!("Skipping {}, it isn't a journal", path.display());
+            return Ok(false);
+        }
+        let Some(key) = Self::unseal(&content[..2 * SEAL_LEN], secret, kdf)? else {
+            // left by another aspect
+            return Ok(false);
+        };
+        let (iv, rest) = content[2 * SEAL_LEN..].split_at(IV_LEN);
+        let (tag, data) = rest.split_at(TAG_LEN);
+        let iv = IV(iv.try_into().unwrap());
+        let tag = Tag(tag.try_into().unwrap());
+        let data = decrypt(data, &key, &iv, b"journal", &tag)?;
+        let journal: Self = bincode::deserialize(&data)?;
+
+        let mut done = true;
+        for region in &journal.regions {
+            let mut current = vec![0; region.data.len()];
+            file.seek(SeekFrom::Start(region.offset))?;
+            file.read_exact(&mut current)?;
+            done &= openssl::sha::sha256(&current) == region.digest;
+        }
+        if !done {
+            for region in &journal.regions {
+                file.seek(SeekFrom::Start(region.offset))?;
+                file.write_all(&region.data)?;
+            }
+            file.sync_all()?;
+        }
+
+        Self::remove(path)?;
+        Ok(true)
+    }
+
+    /// the key of the journal, if either of its seals opens with `secret`
+    fn unseal(seals: &[u8], secret: &[u8], kdf: Kdf) -> Result<Option<Key>> {
+        let mut derived: Option<WrappingKey> = None;
+        for seal in seals.chunks(SEAL_LEN) {
+            let (salt, rest) = seal.split_at(SALT_LEN);
+            let (iv, rest) = rest.split_at(IV_LEN);
+            let (tag, sealed) = rest.split_at(TAG_LEN);
+            // both seals share the salt when the wrapping key doesn't change
+            let wk = match derived {
+                Some(wk) if wk.salt[..] == *salt => wk,
+                _ => WrappingKey::derive(secret, kdf, salt.try_into().unwrap())?,
+            };
+            let iv = IV(iv.try_into().unwrap());
+            let tag = Tag(tag.try_into().unwrap());
+            match decrypt(sealed, &wk.key, &iv, b"journal key", &tag) {
+                Ok(mut key) => {
+                    let journal_key = Key::from_slice(&key);
+                    zeroize(&mut key);
+                    return Ok(Some(journal_key));
+                }
+                Err(PinoqError::Authentication) => derived = Some(wk),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(None)
+    }
+}
+
+fn sync_parent(path: &Path) -> Result<()> {
+    let parent = match path.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+    File::open(parent)?.sync_all()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::OpenOptions;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_recover() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("disk");
+        let disk = path.to_str().unwrap();
+        fs::write(disk, [1; 64]).unwrap();
+        let journals = Journal::default_dir(disk);
+
+        let kdf = Kdf::pbkdf2(1000);
+        let old = WrappingKey::new(b"old", kdf).unwrap();
+        let new = WrappingKey::new(b"new", kdf).unwrap();
+        let other = WrappingKey::new(b"other", kdf).unwrap();
+        let geometry = Geometry::new(false, 64);
+
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(disk)
+            .unwrap();
+        assert!(!Journal::recover(&journals, &mut file, b"old", kdf).unwrap());
+
+        // left by another aspect, it doesn't get in the way
+        let foreign = Journal::default()
+            .commit(&journals, &geometry, &other, &other)
+            .unwrap();
+
+        let mut journal = Journal::default();
+        journal.save(&mut file, 16, vec![2; 8]).unwrap();
+        journal.save(&mut file, 40, vec![2; 8]).unwrap();
+        let committed = journal.commit(&journals, &geometry, &old, &new).unwrap();
+
+        // nothing tells what it holds
+        let content = fs::read(&committed).unwrap();
+        let len = 2 * SEAL_LEN + IV_LEN + TAG_LEN + Journal::padded_len(&geometry);
+        assert_eq!(content.len(), len);
+        assert_eq!(fs::read(&foreign).unwrap().len(), len);
+        assert!(!content.windows(8).any(|w| w == [1; 8]));
+
+        // interrupted in the middle of the rewrite
+        file.seek(SeekFrom::Start(16)).unwrap();
+        file.write_all(&[2; 8]).unwrap();
+        file.seek(SeekFrom::Start(40)).unwrap();
+        file.write_all(&[2; 4]).unwrap();
+
+        // only the aspect that left it can recover it, with either secret
+        assert!(!Journal::recover(&journals, &mut file, b"wrong", kdf).unwrap());
+        assert!(committed.exists());
+        assert!(Journal::recover(&journals, &mut file, b"new", kdf).unwrap());
+        assert_eq!(fs::read(disk).unwrap(), vec![1; 64]);
+        assert!(!committed.exists());
+        assert!(foreign.exists());
+
+        // interrupted once everything was written, the new content stays
+        let committed = journal.commit(&journals, &geometry, &old, &new).unwrap();
+        journal.apply(&mut file).unwrap();
+        assert!(Journal::recover(&journals, &mut file, b"old", kdf).unwrap());
+        let data = fs::read(disk).unwrap();
+        assert_eq!(data[16..24], [2; 8]);
+        assert_eq!(data[40..48], [2; 8]);
+        assert!(!committed.exists());
+
+        assert!(Journal::recover(&journals, &mut file, b"other", kdf).unwrap());
+        assert_eq!(fs::read_dir(&journals).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_stray_files() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("disk");
+        let disk = path.to_str().unwrap();
+        fs::write(disk, [1; 64]).unwrap();
+        let journals = Journal::default_dir(disk);
+        fs::create_dir_all(journals.join("nested")).unwrap();
+        fs::write(journals.join(".DS_Store"), [0; 16]).unwrap();
+
+        let kdf = Kdf::pbkdf2(1000);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(disk)
+            .unwrap();
+        // nothing to recover, and nothing removed
+        assert!(!Journal::recover(&journals, &mut file, b"old", kdf).unwrap());
+        assert!(journals.join(".DS_Store").exists());
+    }
+}
@@ -3,83 +3,479 @@ mod encryption;
 mod error;
 mod filefmt;
 mod fs;
+mod journal;
+pub mod prompt;
+#[cfg(test)]
+mod testing;
 
+pub use encryption::{Kdf, KdfAlgorithm, Secret};
+pub(crate) use error::PinoqError;
+pub use filefmt::Allocation;
+pub(crate) use filefmt::ASPECT_SLOTS;
 pub use fs::PinoqFs;
 
-use config::Config;
-use error::{PinoqError, Result};
-use filefmt::{Aspect, EncryptedAspect, PinoqSerialize, SuperBlock, BLOCK_SIZE};
+use anyhow::Context;
+use config::{Config, Current};
+use encryption::{random_key, WrappingKey};
+use error::Result;
+use filefmt::{
+    from_encrypted_block, to_encrypted_block, Aspect, EncryptedAspect, EncryptedBlock,
+    PinoqSerialize, RawBlock, SuperBlock, BLOCK_SIZE,
+};
+use fs::Problem;
+use journal::Journal;
+
+use std::fs::{File, OpenOptions, Permissions};
+use std::io::{IsTerminal, Read, Seek, SeekFrom, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+/// where everything lives in a volume, none of it is stored in clear
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Geometry {
+    // the superblock, missing in headerless volumes
+    pub header_len: usize,
+    pub blocks: u32,
+}
+
+impl Geometry {
+    pub fn new(headerless: bool, blocks: u32) -> Self {
+        Self {
+            header_len: if headerless { 0 } else { SuperBlock::size_of() },
+            blocks,
+        }
+    }
+
+    /// the number of blocks is the largest multiple of 8
+    /// that fits in a volume of `len` bytes, a volume without room for any is truncated
+    pub fn from_len(headerless: bool, len: usize) -> Result<Self> {
+        let fits = |b: u32| {
+            Self::new(headerless, b * 8)
+                .checked_len()
+                .is_some_and(|l| l <= len)
+        };
+
+        // `lo` always fits while `hi` never does
+        let mut lo = 0;
+        let mut hi = (len / BLOCK_SIZE).min(u32::MAX as usize) as u32 / 8 + 1;
+        while lo + 1 < hi {
+            let mid = lo + (hi - lo) / 2;
+            if fits(mid) {
+                lo = mid;
+            } else {
+                hi = mid;
+            }
+        }
+        match lo {
+            0 => Err(PinoqError::Truncated),
+            _ => Ok(Self::new(headerless, lo * 8)),
+        }
+    }
+
+    #[inline]
+    pub fn aspect_offset(&self, n: u32) -> usize {
+        self.header_len + EncryptedAspect::size_of(self.blocks) * (n as usize)
+    }
+
+    #[inline]
+    pub fn block_offset(&self, n: u32) -> usize {
+        self.aspect_offset(ASPECT_SLOTS) + BLOCK_SIZE * (n as usize)
+        // + std::mem::size_of::<Block>() * (n as usize)
+    }
+
+    pub fn len(&self) -> usize {
+        self.block_offset(self.blocks)
+    }
 
-use std::fs::OpenOptions;
-use std::io::{Read, Seek, SeekFrom, Write};
+    /// same as `len`, unless the volume doesn't fit in the address space
+    pub fn checked_len(&self) -> Option<usize> {
+        let aspects = EncryptedAspect::size_of(self.blocks).checked_mul(ASPECT_SLOTS as _)?;
+        let blocks = BLOCK_SIZE.checked_mul(self.blocks as _)?;
+        self.header_len.checked_add(aspects)?.checked_add(blocks)
+    }
+}
 
-#[inline]
-fn get_block_offset(aspects: u32, blocks: u32, n: u32) -> usize {
-    std::mem::size_of::<SuperBlock>()
-        + EncryptedAspect::size_of(blocks) * (aspects as usize)
-        + BLOCK_SIZE * (n as usize)
-    // + std::mem::size_of::<Block>() * (n as usize)
+impl std::fmt::Display for Geometry {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "superblock: {} bytes", self.header_len)?;
+        writeln!(
+            f,
+            "aspects:    {} slots of {} bytes at {}",
+            ASPECT_SLOTS,
+            EncryptedAspect::size_of(self.blocks),
+            self.aspect_offset(0)
+        )?;
+        writeln!(
+            f,
+            "blocks:     {} blocks of {} bytes at {}",
+            self.blocks,
+            BLOCK_SIZE,
+            self.block_offset(0)
+        )?;
+        write!(f, "total:      {} bytes", self.len())
+    }
 }
 
-#[inline]
-fn get_aspect_offset(blocks: u32, n: u32) -> usize {
-    std::mem::size_of::<SuperBlock>() + EncryptedAspect::size_of(blocks) * (n as usize)
+/// the length of a volume, block devices only tell it by seeking to their end
+/// (their metadata says 0), the position is put back at the start
+fn volume_len<S: Seek>(volume: &mut S) -> std::io::Result<usize> {
+    let len = volume.seek(SeekFrom::End(0))?;
+    volume.seek(SeekFrom::Start(0))?;
+    Ok(len as _)
 }
 
-fn decrypt_aspect<R>(mut reader: R, offset: usize, password: &str) -> Result<Aspect>
+/// returns the aspect along with the key derived from the secret
+/// so it can be stored again without running the KDF
+fn decrypt_aspect<R>(
+    mut reader: R,
+    geometry: &Geometry,
+    n: u32,
+    kdf: Kdf,
+    secret: &[u8],
+) -> Result<(Aspect, WrappingKey)>
 where
     R: Read,
     R: Seek,
 {
     reader
-        .seek(SeekFrom::Start(offset as _))
-        .map_err(|e| PinoqError::IO(e))?;
-    let encrypted = EncryptedAspect::deserialize_from(reader)?;
-    Aspect::from_encrypted_aspect(encrypted, password)
+        .seek(SeekFrom::Start(geometry.aspect_offset(n) as _))
+        .map_err(PinoqError::IO)?;
+    let encrypted = EncryptedAspect::deserialize_from(reader, geometry.blocks)?;
+    let wk = encrypted.wrapping_key(secret, kdf)?;
+    // an unused slot is random data, which can't be told apart from a wrong password
+    let aspect = Aspect::from_encrypted_aspect(encrypted, &wk, n).map_err(|e| match e {
+        PinoqError::Authentication => PinoqError::WrongPassword,
+        e => e,
+    })?;
+    Ok((aspect, wk))
 }
 
-fn encrypt_aspect<W>(mut writer: W, offset: usize, aspect: Aspect, password: &str) -> Result<()>
+fn encrypt_aspect<W>(
+    mut writer: W,
+    geometry: &Geometry,
+    n: u32,
+    aspect: &Aspect,
+    wk: &WrappingKey,
+) -> Result<()>
 where
     W: Write,
     W: Seek,
 {
     writer
-        .seek(SeekFrom::Start(offset as _))
-        .map_err(|e| PinoqError::IO(e))?;
-    let encrypted = aspect.to_encrypted_aspect(password);
+        .seek(SeekFrom::Start(geometry.aspect_offset(n) as _))
+        .map_err(PinoqError::IO)?;
+    let encrypted = aspect.to_encrypted_aspect(wk, n)?;
     encrypted.serialize_into(&mut writer)
 }
 
-pub fn mount(config: Config) {
+pub fn mount(config: Config) -> anyhow::Result<()> {
     let mountpoint = config.mount.clone();
-    let fs = PinoqFs::new(config).unwrap();
-    let _ = fuser::mount2(
+    let fs = PinoqFs::new(config)?;
+    fuser::mount2(
         fs,
-        mountpoint,
+        &mountpoint,
         &[
             fuser::MountOption::AutoUnmount,
             fuser::MountOption::AllowOther,
+            fuser::MountOption::DefaultPermissions,
         ],
-    );
+    )
+    .with_context(|| format!("Couldn't mount on {}", mountpoint))
+}
+
+pub fn umount(mountpoint: &str) -> anyhow::Result<()> {
+    let status = std::process::Command::new("fusermount")
+        .args(["-u", mountpoint])
+        .status()
+        .context("Couldn't run fusermount")?;
+    if !status.success() {
+        anyhow::bail!("Couldn't unmount {}", mountpoint);
+    }
+    Ok(())
+}
+
+#[derive(Debug, Default)]
+pub struct MkfsOptions {
+    pub kdf: Kdf,
+    /// leave the unused blocks zeroed instead of filling them with random data
+    /// it's much faster, but reveals how much data the volume holds
+    pub fast: bool,
+    /// don't write the superblock, the volume looks like random data
+    /// the KDF parameters have to be given again when mounting
+    pub headerless: bool,
+    pub allocation: Allocation,
+}
+
+/// how large a new volume is
+#[derive(Debug, Clone, Copy)]
+pub enum Size {
+    Blocks(u32),
+    /// as many blocks as fit in this many bytes
+    Bytes(u64),
+    /// as many blocks as fit in the file or block device already at the path,
+    /// whatever it holds is overwritten
+    Existing,
 }
 
-pub fn mkfs(aspects: u32, blocks: u32, path: &str, pass: &str) -> anyhow::Result<()> {
-    let mut file = OpenOptions::new().write(true).create_new(true).open(path)?;
+/// creates a volume with one aspect for each of the `secrets`, returns its layout
+pub fn mkfs<S>(
+    secrets: &[S],
+    size: Size,
+    path: &str,
+    options: &MkfsOptions,
+) -> anyhow::Result<Geometry>
+where
+    S: AsRef<[u8]>,
+{
+    let aspects = secrets.len() as u32;
+    if aspects == 0 || aspects > ASPECT_SLOTS {
+        anyhow::bail!(
+            "the number of aspects must be between 1 and {}",
+            ASPECT_SLOTS
+        );
+    }
 
-    let length = get_block_offset(aspects, blocks, blocks);
-    file.set_len(length as _)?;
+    let (geometry, end) = match size {
+        Size::Blocks(blocks) => {
+            if blocks == 0 || !blocks.is_multiple_of(8) {
+                anyhow::bail!("the number of blocks must be a positive multiple of 8");
+            }
+            (Geometry::new(options.headerless, blocks), None)
+        }
+        Size::Bytes(len) => {
+            let len = usize::try_from(len).unwrap_or(usize::MAX);
+            let geometry = Geometry::from_len(options.headerless, len)
+                .ok()
+                .with_context(|| format!("{} bytes are too few for a volume", len))?;
+            (geometry, None)
+        }
+        Size::Existing => {
+            // the old content would stay readable, and tell the used blocks apart
+            if options.fast {
+                anyhow::bail!("an existing file or device is always filled with random data");
+            }
+            let len = volume_len(&mut File::open(path)?)?;
+            let geometry = Geometry::from_len(options.headerless, len)
+                .ok()
+                .with_context(|| format!("{} is too small for a volume", path))?;
+            (geometry, Some(len))
+        }
+    };
+    let Some(length) = geometry.checked_len() else {
+        anyhow::bail!("a volume of {} blocks is too large", geometry.blocks);
+    };
 
+    let mut file = match end {
+        Some(_) => OpenOptions::new().write(true).open(path)?,
+        None => {
+            let file = OpenOptions::new().write(true).create_new(true).open(path)?;
+            file.set_len(length as _)?;
+            file
+        }
+    };
     let uid = unsafe { libc::getuid() };
     let gid = unsafe { libc::getgid() };
 
-    let sblock = SuperBlock::new(aspects, blocks, uid, gid);
-    sblock.serialize_into(&mut file)?;
+    if !options.headerless {
+        let sblock = SuperBlock::new(options.kdf);
+        sblock.serialize_into(&mut file)?;
+    }
 
-    for _ in 0..aspects {
-        let aspect = Aspect::new(blocks);
-        let encrypted = aspect.to_encrypted_aspect(pass);
+    for (i, secret) in (0..).zip(secrets) {
+        let mut aspect = Aspect::new(geometry.blocks, uid, gid);
+        aspect.allocation = options.allocation;
+        // every aspect gets its own salt
+        let wk = WrappingKey::new(secret.as_ref(), options.kdf)?;
+        let encrypted = aspect.to_encrypted_aspect(&wk, i)?;
         encrypted.serialize_into(&mut file)?;
     }
+    // the unused slots look just like the used ones
+    let offset = geometry.aspect_offset(aspects);
+    fill_random(&mut file, offset, geometry.block_offset(0) - offset)?;
+
+    // the room left after the last block of an existing file is covered too
+    if !options.fast {
+        let offset = geometry.block_offset(0);
+        fill_random(&mut file, offset, end.unwrap_or(length) - offset)?;
+    }
+
+    Ok(geometry)
+}
+
+/// makes the unused blocks indistinguishable from the encrypted ones
+fn fill_random<W>(mut writer: W, offset: usize, len: usize) -> anyhow::Result<()>
+where
+    W: Write,
+    W: Seek,
+{
+    const CHUNK_SIZE: usize = 1 << 20;
+
+    let progress = std::io::stderr().is_terminal();
+    writer.seek(SeekFrom::Start(offset as _))?;
+
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    let mut written = 0;
+    while written < len {
+        let n = CHUNK_SIZE.min(len - written);
+        rand::fill(&mut buf[..n]);
+        writer.write_all(&buf[..n])?;
+        written += n;
+
+        if progress {
+            eprint!(
+                "\rFilling the volume with random data: {}%",
+                written * 100 / len
+            );
+        }
+    }
+    if progress {
+        eprintln!();
+    }
+
+    Ok(())
+}
+
+/// opens the volume of `config`, after putting back what an interrupted update of
+/// the aspect unlocked by `secret` left. returns the KDF of its aspects along with its geometry
+/// the volume stays locked until the file is closed, so it's never opened twice
+fn open_volume(config: &Config, secret: &[u8]) -> Result<(File, Kdf, Geometry)> {
+    let aspects = std::iter::once(&config.current).chain(&config.protect);
+    if aspects.into_iter().any(|c| c.aspect >= ASPECT_SLOTS) {
+        return Err(PinoqError::InvalidConfig);
+    }
+
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&config.disk)?;
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+        let e = std::io::Error::last_os_error();
+        return Err(match e.kind() {
+            std::io::ErrorKind::WouldBlock => PinoqError::Busy,
+            _ => e.into(),
+        });
+    }
+    let geometry = Geometry::from_len(config.headerless, volume_len(&mut file)?)?;
+    let kdf = if config.headerless {
+        config.kdf
+    } else {
+        SuperBlock::deserialize_from(&mut file)?.kdf
+    };
+    // the journals of the other aspects are left as they are, without a word
+    if Journal::recover(&config.journal_dir(), &mut file, secret, kdf)? {
+        log::warn!("Recovered the volume from an interrupted update");
+    }
+    Ok((file, kdf, geometry))
+}
+
+/// stores the aspect `n` wrapped with `wk`, the old slot is kept aside in `journals` until
+/// the new one is on the disk. `unlocked` is the key the slot was opened with, the journal
+/// can be recovered with either secret
+fn rewrite_aspect(
+    file: &mut File,
+    journals: &Path,
+    geometry: &Geometry,
+    n: u32,
+    aspect: &Aspect,
+    wk: &WrappingKey,
+    unlocked: &WrappingKey,
+) -> Result<()> {
+    let mut slot = vec![];
+    aspect
+        .to_encrypted_aspect(wk, n)?
+        .serialize_into(&mut slot)?;
+    let mut journal = Journal::default();
+    journal.save(file, geometry.aspect_offset(n) as _, slot)?;
+    let committed = journal.commit(journals, geometry, unlocked, wk)?;
+
+    journal.apply(file)?;
+    Journal::remove(&committed)
+}
+
+/// re-wraps the aspect of `config` with the secret returned by `new_secret`, in place
+/// it's only asked once the aspect is unlocked with the current one
+/// the blocks and the key encrypting them stay the same
+/// the volume must not be mounted meanwhile
+pub fn passwd<F>(config: &Config, new_secret: F) -> anyhow::Result<()>
+where
+    F: FnOnce() -> anyhow::Result<Secret>,
+{
+    let secret = config.current.secret()?;
+    let (mut file, kdf, geometry) = open_volume(config, &secret)?;
+
+    let n = config.current.aspect;
+    let (aspect, unlocked) = decrypt_aspect(&mut file, &geometry, n, kdf, &secret)?;
+    let wk = WrappingKey::new(&new_secret()?, kdf)?;
+    rewrite_aspect(
+        &mut file,
+        &config.journal_dir(),
+        &geometry,
+        n,
+        &aspect,
+        &wk,
+        &unlocked,
+    )?;
+
+    Ok(())
+}
+
+/// replaces the key encrypting the blocks of the aspect of `config`
+/// the new key is only used once every block is rewritten with it, an interrupted
+/// run is resumed by running it again. the volume must not be mounted meanwhile
+pub fn rekey(config: &Config) -> anyhow::Result<()> {
+    let secret = config.current.secret()?;
+    let (mut file, kdf, geometry) = open_volume(config, &secret)?;
+
+    let n = config.current.aspect;
+    let journals = config.journal_dir();
+    let (mut aspect, wk) = decrypt_aspect(&mut file, &geometry, n, kdf, &secret)?;
+    let next_key = match &aspect.next_key {
+        Some(key) => {
+            log::info!("Resuming an interrupted re-key");
+            key.clone()
+        }
+        None => {
+            let key = random_key();
+            aspect.next_key = Some(key.clone());
+            rewrite_aspect(&mut file, &journals, &geometry, n, &aspect, &wk, &wk)?;
+            key
+        }
+    };
+
+    let owned: Vec<u32> = aspect.block_map.iter_ones().map(|b| b as u32).collect();
+    // the blocks are rewritten in batches, each one journaled on its own
+    for batch in owned.chunks(journal::MAX_BLOCKS) {
+        let mut journal = Journal::default();
+        for &b in batch {
+            let offset = geometry.block_offset(b) as u64;
+            file.seek(SeekFrom::Start(offset))?;
+            let eb = EncryptedBlock::deserialize_from(&mut file)?;
+
+            // the tag tells which key a block is encrypted with
+            if from_encrypted_block::<RawBlock>(&eb, &next_key, b).is_ok() {
+                continue;
+            }
+            let Ok(raw) = from_encrypted_block::<RawBlock>(&eb, &aspect.key, b) else {
+                log::warn!("Block {} can't be decrypted, leaving it as is", b);
+                continue;
+            };
+            let mut new = vec![];
+            to_encrypted_block(&raw, &next_key, b)?.serialize_into(&mut new)?;
+            journal.save(&mut file, offset, new)?;
+        }
+        if journal.regions.is_empty() {
+            continue;
+        }
+
+        let committed = journal.commit(&journals, &geometry, &wk, &wk)?;
+        journal.apply(&mut file)?;
+        Journal::remove(&committed)?;
+    }
+
+    aspect.key = next_key;
+    aspect.next_key = None;
+    rewrite_aspect(&mut file, &journals, &geometry, n, &aspect, &wk, &wk)?;
 
     Ok(())
 }
@@ -87,14 +483,186 @@ pub fn mkfs(aspects: u32, blocks: u32, path: &str, pass: &str) -> anyhow::Result
 pub fn inspect(path: &str) -> anyhow::Result<()> {
     let sblock = PinoqFs::inspect(path)?;
     println!(
-        r#"{{"path": "{}", "magic": "{:#X}", "aspects": {}, "blocks": {}}}"#,
-        path, sblock.magic, sblock.aspects, sblock.blocks
+        r#"{{"path": {}, "magic": "{:#X}", "version": {}}}"#,
+        json_string(path),
+        sblock.magic,
+        sblock.version
     );
     Ok(())
 }
 
+/// checks the aspect of `config`, one line of JSON is printed for every problem
+/// the other aspects are never looked at, except the protected ones
+pub fn fsck(config: Config, repair: bool) -> anyhow::Result<()> {
+    let mut fs = PinoqFs::open(config)?;
+    let problems = fs.check(repair)?;
+
+    let mut left = 0;
+    for problem in &problems {
+        let fields = match problem {
+            Problem::MissingRoot => r#""problem": "missing_root""#.to_string(),
+            Problem::DanglingEntry { dir, name } => format!(
+                r#""problem": "dangling_entry", "dir": {}, "name": {}"#,
+                dir,
+                json_string(name)
+            ),
+            Problem::BrokenChain { inode, block } => format!(
+                r#""problem": "broken_chain", "inode": {}, "block": {}"#,
+                inode, block
+            ),
+            Problem::LeakedBlock(block) => {
+                format!(r#""problem": "leaked_block", "block": {}"#, block)
+            }
+            Problem::UnmarkedBlock(block) => {
+                format!(r#""problem": "unmarked_block", "block": {}"#, block)
+            }
+            Problem::SharedBlock { block, aspect } => format!(
+                r#""problem": "shared_block", "block": {}, "aspect": {}"#,
+                block, aspect
+            ),
+        };
+        let repaired = repair && problem.is_repairable(&problems);
+        if !repaired {
+            left += 1;
+        }
+        println!(r#"{{{}, "repaired": {}}}"#, fields, repaired);
+    }
+
+    if left > 0 {
+        anyhow::bail!("{} problems left", left);
+    }
+    Ok(())
+}
+
+/// lists the directory at `path` of the volume of `config`, directories end with `/`
+pub fn ls(config: Config, path: &str) -> anyhow::Result<()> {
+    let fs = PinoqFs::open(config)?;
+    for (name, node) in fs.read_dir(path)? {
+        println!("{}{}", name, if node.is_dir() { "/" } else { "" });
+    }
+    Ok(())
+}
+
+pub fn cat(config: Config, path: &str) -> anyhow::Result<()> {
+    let mut fs = PinoqFs::open(config)?;
+    fs.read_file(path, std::io::stdout().lock())?;
+    Ok(())
+}
+
+/// stores stdin in the file at `path` of the volume of `config`
+/// the passwords can't be read from stdin as well, the first line would be taken for one
+pub fn put(config: Config, path: &str) -> anyhow::Result<()> {
+    if config.current.reads_stdin() || config.protect.iter().any(Current::reads_stdin) {
+        anyhow::bail!(
+            "put reads the file from stdin, set `password` or `password_from` to something else \
+             in the config, or use cp"
+        );
+    }
+    let mut fs = PinoqFs::new(config)?;
+    fs.write_file(path, 0o644, std::io::stdin().lock())?;
+    Ok(())
+}
+
+pub fn rm(config: Config, path: &str, recursive: bool) -> anyhow::Result<()> {
+    // an aspect without a root has nothing to remove, it's not given one
+    let mut fs = PinoqFs::open(config)?;
+    fs.remove(path, recursive)?;
+    Ok(())
+}
+
+/// copies between the host and the volume of `config`, paths in the volume start with `:`
+/// like cp, a copy to a directory ends up inside it
+pub fn cp(config: Config, src: &str, dst: &str, recursive: bool) -> anyhow::Result<()> {
+    match (src.strip_prefix(':'), dst.strip_prefix(':')) {
+        (None, Some(dst)) => copy_in(&mut PinoqFs::new(config)?, Path::new(src), dst, recursive),
+        // the volume is only read
+        (Some(src), None) => copy_out(&mut PinoqFs::open(config)?, src, Path::new(dst), recursive),
+        _ => anyhow::bail!("exactly one of the paths must be in the volume, starting with `:`"),
+    }
+}
+
+fn copy_in(fs: &mut PinoqFs, src: &Path, dst: &str, recursive: bool) -> anyhow::Result<()> {
+    // links aren't followed, they may loop or lead out of the copied tree
+    let metadata = std::fs::symlink_metadata(src)?;
+    if !metadata.is_file() && !metadata.is_dir() {
+        log::warn!(
+            "Skipping {}, only files and directories are copied",
+            src.display()
+        );
+        return Ok(());
+    }
+    let perm = metadata.permissions().mode();
+    let dst = match fs.metadata(dst) {
+        Ok(node) if node.is_dir() => match src.file_name() {
+            Some(name) => format!("{}/{}", dst, name.to_string_lossy()),
+            None => dst.to_string(),
+        },
+        _ => dst.to_string(),
+    };
+
+    if !metadata.is_dir() {
+        fs.write_file(&dst, perm, File::open(src)?)?;
+        return Ok(());
+    }
+    if !recursive {
+        anyhow::bail!("{} is a directory", src.display());
+    }
+    if fs.metadata(&dst).is_err() {
+        fs.create_dir(&dst, perm)?;
+    }
+    for entry in std::fs::read_dir(src)? {
+        copy_in(fs, &entry?.path(), &dst, true)?;
+    }
+    Ok(())
+}
+
+fn copy_out(fs: &mut PinoqFs, src: &str, dst: &Path, recursive: bool) -> anyhow::Result<()> {
+    let node = fs.metadata(src)?;
+    let name = src
+        .trim_end_matches('/')
+        .rsplit('/')
+        .next()
+        .unwrap_or_default();
+    let dst = match dst.is_dir() && !name.is_empty() {
+        true => dst.join(name),
+        false => dst.to_path_buf(),
+    };
+
+    if !node.is_dir() {
+        fs.read_file(src, File::create(&dst)?)?;
+        std::fs::set_permissions(&dst, Permissions::from_mode(node.perm() as _))?;
+        return Ok(());
+    }
+    if !recursive {
+        anyhow::bail!("{} is a directory", src);
+    }
+    if !dst.is_dir() {
+        std::fs::create_dir(&dst)?;
+    }
+    for (child, _) in fs.read_dir(src)? {
+        copy_out(fs, &format!("{}/{}", src, child), &dst, true)?;
+    }
+    Ok(())
+}
+
+/// `s` quoted and escaped
+fn json_string(s: &str) -> String {
+    let mut out = String::from('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
 #[cfg(test)]
 mod tests {
+    use super::testing::*;
     use super::*;
     use tempfile::tempdir;
 
@@ -104,34 +672,357 @@ mod tests {
         let path = dir.path().join("my-volume.pnoq");
         let path = path.to_str().unwrap();
 
-        mkfs(2, 512, path, "password").unwrap();
+        let options = MkfsOptions::default();
+        mkfs(&["password", "passw0rd"], Size::Blocks(512), path, &options).unwrap();
         let sblock = PinoqFs::inspect(path).unwrap();
         assert_eq!(sblock.magic, 0x504E4F51u32);
-        assert_eq!(sblock.aspects, 2);
-        assert_eq!(sblock.blocks, 512);
+        assert_eq!(sblock.version, 1);
+
+        // a single aspect takes the same room as many
+        let other = dir.path().join("other.pnoq");
+        let other = other.to_str().unwrap();
+        mkfs(&["password"], Size::Blocks(512), other, &options).unwrap();
+        let len = std::fs::metadata(path).unwrap().len();
+        assert_eq!(std::fs::metadata(other).unwrap().len(), len);
+        assert_eq!(Geometry::from_len(false, len as _).unwrap().blocks, 512);
+
+        let passwords = vec!["password"; ASPECT_SLOTS as usize + 1];
+        assert!(mkfs(&passwords, Size::Blocks(512), other, &options).is_err());
+        assert!(mkfs::<&str>(&[], Size::Blocks(512), other, &options).is_err());
+        assert!(mkfs(&["password"], Size::Blocks(500), other, &options).is_err());
 
         dir.close().unwrap();
     }
 
+    #[test]
+    fn test_volume_size() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("my-volume.pnoq");
+        let path = path.to_str().unwrap();
+
+        let options = MkfsOptions {
+            kdf: Kdf::pbkdf2(1000),
+            ..Default::default()
+        };
+        let geometry = mkfs(&["password"], Size::Bytes(1 << 20), path, &options).unwrap();
+        assert!(geometry.len() <= 1 << 20);
+        assert!(geometry.len() + 8 * BLOCK_SIZE > 1 << 20);
+        assert_eq!(
+            std::fs::metadata(path).unwrap().len(),
+            geometry.len() as u64
+        );
+
+        // an existing file keeps its length, the room after the last block included
+        let existing = dir.path().join("existing");
+        let existing = existing.to_str().unwrap();
+        std::fs::write(existing, vec![0; (1 << 20) + 100]).unwrap();
+        let wrapped = mkfs(&["password"], Size::Existing, existing, &options).unwrap();
+        assert_eq!(wrapped, geometry);
+        let data = std::fs::read(existing).unwrap();
+        assert_eq!(data.len(), (1 << 20) + 100);
+        let mut file = File::open(existing).unwrap();
+        assert_eq!(volume_len(&mut file).unwrap(), data.len());
+        assert_eq!(file.stream_position().unwrap(), 0);
+        assert_ne!(data[data.len() - 100..], [0; 100]);
+        let fast = MkfsOptions {
+            fast: true,
+            ..options
+        };
+        assert!(mkfs(&["password"], Size::Existing, existing, &fast).is_err());
+
+        let config = test_config(existing, 0, "password");
+        assert!(PinoqFs::new(config).is_ok());
+
+        let small = dir.path().join("small.pnoq");
+        let small = small.to_str().unwrap();
+        assert!(mkfs(&["password"], Size::Bytes(4096), small, &options).is_err());
+        assert!(mkfs(&["password"], Size::Existing, small, &options).is_err());
+        // nothing is left behind by a rejected size
+        assert!(!dir.path().join("small.pnoq").exists());
+    }
+
     #[test]
     fn test_offsets() {
-        let aspects = 2;
+        let aspects = ASPECT_SLOTS;
         let blocks = 256;
 
-        let sblock_len = std::mem::size_of::<SuperBlock>();
+        let sblock_len = SuperBlock::size_of();
         let aspect_len = EncryptedAspect::size_of(blocks);
+        let geometry = Geometry::new(false, blocks);
 
-        let offset = get_aspect_offset(blocks, 0);
+        let offset = geometry.aspect_offset(0);
         assert_eq!(offset, sblock_len);
-        let offset = get_aspect_offset(blocks, 1);
+        let offset = geometry.aspect_offset(1);
         assert_eq!(offset, sblock_len + aspect_len);
 
-        let offset = get_block_offset(aspects, blocks, 0);
+        let offset = geometry.block_offset(0);
         assert_eq!(offset, sblock_len + aspect_len * (aspects as usize));
-        let offset = get_block_offset(aspects, blocks, 1);
+        let offset = geometry.block_offset(1);
         assert_eq!(
             offset,
             sblock_len + aspect_len * (aspects as usize) + BLOCK_SIZE
         );
+
+        let len = geometry.len();
+        assert_eq!(Geometry::from_len(false, len).unwrap(), geometry);
+        assert_eq!(
+            Geometry::from_len(false, len + BLOCK_SIZE).unwrap(),
+            geometry
+        );
+        assert_eq!(
+            Geometry::from_len(false, len - 1).unwrap().blocks,
+            blocks - 8
+        );
+        assert!(matches!(
+            Geometry::from_len(false, 0),
+            Err(PinoqError::Truncated)
+        ));
+
+        // headerless volumes start right away with the aspects
+        let geometry = Geometry::new(true, blocks);
+        assert_eq!(geometry.aspect_offset(0), 0);
+        assert_eq!(Geometry::from_len(true, geometry.len()).unwrap(), geometry);
+    }
+
+    #[test]
+    fn test_random_blocks() {
+        let options = MkfsOptions {
+            fast: false,
+            ..test_options()
+        };
+        let (_dir, path) = test_volume_with(&["password"], Size::Blocks(256), &options);
+
+        let data = std::fs::read(path).unwrap();
+        let blocks = &data[Geometry::new(false, 256).block_offset(0)..];
+        assert_eq!(blocks.len(), 256 * BLOCK_SIZE);
+        // no block is left zeroed
+        assert!(blocks.chunks(BLOCK_SIZE).all(|b| b.iter().any(|&x| x != 0)));
+        // roughly half of the bits are set
+        let ones: u32 = blocks.iter().map(|x| x.count_ones()).sum();
+        let ratio = ones as f64 / (blocks.len() * 8) as f64;
+        assert!((0.49..0.51).contains(&ratio));
+    }
+
+    #[test]
+    fn test_headerless_volume() {
+        let options = MkfsOptions {
+            headerless: true,
+            ..test_options()
+        };
+        let (_dir, path) = test_volume_with(&["password"], Size::Blocks(256), &options);
+
+        let data = std::fs::read(&path).unwrap();
+        assert_eq!(data.len(), Geometry::new(true, 256).len());
+        assert!(!data.windows(4).any(|w| w == 0x504E4F51u32.to_le_bytes()));
+        assert!(matches!(PinoqFs::inspect(&path), Err(PinoqError::BadMagic)));
+
+        let config = Config {
+            headerless: true,
+            kdf: Kdf::pbkdf2(1000),
+            ..test_config(&path, 0, "password")
+        };
+        assert!(PinoqFs::new(config).is_ok());
+
+        // the KDF parameters must match the ones given to mkfs
+        let config = Config {
+            headerless: true,
+            kdf: Kdf::pbkdf2(1001),
+            ..test_config(&path, 0, "password")
+        };
+        assert!(matches!(
+            PinoqFs::new(config),
+            Err(PinoqError::WrongPassword)
+        ));
+    }
+
+    #[test]
+    fn test_passwd() {
+        let (_dir, path) = test_volume(&["old", "other"], 256);
+
+        let config = |aspect: u32, password: &str| test_config(&path, aspect, password);
+        let geometry =
+            Geometry::from_len(false, std::fs::metadata(&path).unwrap().len() as _).unwrap();
+        let unlock = |n: u32, password: &str| {
+            let file = std::fs::File::open(&path).unwrap();
+            let kdf = test_options().kdf;
+            decrypt_aspect(file, &geometry, n, kdf, password.as_bytes()).map(|(a, _)| a)
+        };
+        let key = unlock(0, "old").unwrap().key;
+
+        assert!(passwd(&config(0, "wrong"), || unreachable!()).is_err());
+        passwd(&config(0, "old"), || Ok(Secret::new(b"new".to_vec()))).unwrap();
+        let journals = config(0, "new").journal_dir();
+        assert_eq!(std::fs::read_dir(journals).unwrap().count(), 0);
+
+        assert!(unlock(0, "old").is_err());
+        // the blocks are still encrypted with the same key
+        assert_eq!(unlock(0, "new").unwrap().key.0, key.0);
+        // the other aspects are left alone
+        assert!(unlock(1, "other").is_ok());
+    }
+
+    #[test]
+    fn test_interrupted_passwd() {
+        let (_dir, path) = test_volume(&["first"], 256);
+        let config = |password: &str| test_config(&path, 0, password);
+        let kdf = test_options().kdf;
+        let journals = config("first").journal_dir();
+        let no_journal = || std::fs::read_dir(&journals).unwrap().count() == 0;
+
+        // the new slot is on the disk, but the journal is still there
+        let interrupt = |from: &str, to: &str| {
+            let mut file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(&path)
+                .unwrap();
+            let geometry = Geometry::from_len(false, volume_len(&mut file).unwrap()).unwrap();
+            let (aspect, unlocked) =
+                decrypt_aspect(&mut file, &geometry, 0, kdf, from.as_bytes()).unwrap();
+            let wk = WrappingKey::new(to.as_bytes(), kdf).unwrap();
+            let mut slot = vec![];
+            let encrypted = aspect.to_encrypted_aspect(&wk, 0).unwrap();
+            encrypted.serialize_into(&mut slot).unwrap();
+            let mut journal = Journal::default();
+            let offset = geometry.aspect_offset(0) as _;
+            journal.save(&mut file, offset, slot).unwrap();
+            journal
+                .commit(&journals, &geometry, &unlocked, &wk)
+                .unwrap();
+            journal.apply(&mut file).unwrap();
+        };
+
+        // the new password finishes it, and doesn't get in the way of the next change
+        interrupt("first", "second");
+        assert!(PinoqFs::open(config("second")).is_ok());
+        assert!(no_journal());
+        passwd(&config("second"), || Ok(Secret::new(b"third".to_vec()))).unwrap();
+
+        // the old one drops the journal without undoing the change
+        interrupt("third", "fourth");
+        let result = PinoqFs::open(config("third"));
+        assert!(matches!(result, Err(PinoqError::WrongPassword)));
+        assert!(no_journal());
+        assert!(PinoqFs::open(config("fourth")).is_ok());
+    }
+
+    #[test]
+    fn test_foreign_journal() {
+        let (dir, path) = test_volume(&["decoy", "hidden"], 256);
+        let journals = dir.path().join("journals");
+        let config = |aspect: u32, password: &str| Config {
+            journal: Some(journals.to_str().unwrap().to_string()),
+            ..test_config(&path, aspect, password)
+        };
+        let kdf = test_options().kdf;
+
+        // an interrupted update of the hidden aspect
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .unwrap();
+        let geometry = Geometry::from_len(false, volume_len(&mut file).unwrap()).unwrap();
+        let (_, wk) = decrypt_aspect(&mut file, &geometry, 1, kdf, b"hidden").unwrap();
+        let mut journal = Journal::default();
+        journal.save(&mut file, 0, vec![]).unwrap();
+        let foreign = journal.commit(&journals, &geometry, &wk, &wk).unwrap();
+        drop(file);
+
+        // the decoy neither notices it nor is kept from changing its password
+        assert!(PinoqFs::open(config(0, "decoy")).is_ok());
+        passwd(&config(0, "decoy"), || Ok(Secret::new(b"new".to_vec()))).unwrap();
+        assert!(foreign.exists());
+        assert!(PinoqFs::open(config(0, "new")).is_ok());
+        assert!(!Journal::default_dir(&path).exists());
+
+        assert!(PinoqFs::open(config(1, "hidden")).is_ok());
+        assert!(!foreign.exists());
+
+        // a file that isn't a journal doesn't keep the volume from opening
+        std::fs::write(journals.join("notes.txt~"), b"junk").unwrap();
+        assert!(PinoqFs::open(config(0, "new")).is_ok());
+        assert!(ls(config(1, "hidden"), "/").is_ok());
+    }
+
+    #[test]
+    fn test_copy() {
+        let (dir, path) = test_volume(&["password"], 256);
+        let config = || test_config(&path, 0, "password");
+
+        let src = dir.path().join("src");
+        std::fs::create_dir_all(src.join("nested")).unwrap();
+        std::fs::write(src.join("file"), b"the quick brown fox").unwrap();
+        std::fs::write(src.join("nested/other"), vec![3; 10000]).unwrap();
+        std::os::unix::fs::symlink(&src, src.join("nested/loop")).unwrap();
+        let src = src.to_str().unwrap();
+
+        assert!(cp(config(), src, ":/", false).is_err());
+        cp(config(), src, ":/", true).unwrap();
+        // copied to an existing directory, so it ends up inside
+        cp(config(), src, ":/src", true).unwrap();
+        assert!(cp(config(), src, "/tmp", true).is_err());
+
+        let fs = PinoqFs::new(config()).unwrap();
+        let names: Vec<_> = fs
+            .read_dir("/src")
+            .unwrap()
+            .into_iter()
+            .map(|e| e.0)
+            .collect();
+        assert_eq!(names, ["file", "nested", "src"]);
+        assert!(fs.metadata("/src/nested/loop").is_err());
+        drop(fs);
+
+        let dst = dir.path().join("dst");
+        cp(config(), ":/src", dst.to_str().unwrap(), true).unwrap();
+        assert_eq!(
+            std::fs::read(dst.join("file")).unwrap(),
+            b"the quick brown fox"
+        );
+        assert_eq!(
+            std::fs::read(dst.join("nested/other")).unwrap(),
+            vec![3; 10000]
+        );
+        assert_eq!(
+            std::fs::read(dst.join("src/file")).unwrap(),
+            b"the quick brown fox"
+        );
+
+        rm(config(), "/src", true).unwrap();
+        let fs = PinoqFs::new(config()).unwrap();
+        assert!(fs.read_dir("/").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_put_password_from_stdin() {
+        let (_dir, path) = test_volume(&["password"], 256);
+        let mut config = test_config(&path, 0, "");
+        config.current.password = None;
+        let before = std::fs::read(&path).unwrap();
+
+        // stdin holds the content of the file, it's never read for the password
+        assert!(put(config, "/file").is_err());
+        assert_eq!(std::fs::read(&path).unwrap(), before);
+    }
+
+    #[test]
+    fn test_read_only() {
+        let (dir, path) = test_volume(&["password"], 256);
+        let config = || test_config(&path, 0, "password");
+        let before = std::fs::read(&path).unwrap();
+
+        // an aspect without a root is listed as empty
+        ls(config(), "/").unwrap();
+        let fs = PinoqFs::open(config()).unwrap();
+        assert!(fs.read_dir("/").unwrap().is_empty());
+        assert!(matches!(fs.read_dir("/dir"), Err(PinoqError::NoEntry)));
+        drop(fs);
+        let error = cat(config(), "/file").unwrap_err();
+        assert!(matches!(error.downcast_ref(), Some(PinoqError::NoEntry)));
+        assert!(rm(config(), "/file", false).is_err());
+        let dst = dir.path().join("dst");
+        assert!(cp(config(), ":/file", dst.to_str().unwrap(), false).is_err());
+        assert_eq!(std::fs::read(&path).unwrap(), before);
     }
 }
@@ -0,0 +1,90 @@
+use std::io::{BufRead, ErrorKind, IsTerminal};
+
+use crate::pinoq::encryption::Secret;
+use crate::pinoq::error::{PinoqError, Result};
+
+/// asks for a password on the terminal without echoing it
+/// when stdin isn't a terminal (e.g. a pipe), a line is read from it instead
+pub fn read_password(prompt: &str) -> Result<Secret> {
+    let stdin = std::io::stdin();
+    if !stdin.is_terminal() {
+        return read_line(stdin.lock());
+    }
+
+    eprint!("{}", prompt);
+    let password = {
+        let _echo = EchoOff::new()?;
+        read_line(stdin.lock())
+    };
+    eprintln!();
+    password
+}
+
+/// same as `read_password`, but asks twice on a terminal to catch typos
+pub fn read_new_password(prompt: &str) -> Result<Secret> {
+    let password = read_password(prompt)?;
+    if std::io::stdin().is_terminal() && read_password("Repeat: ")?[..] != password[..] {
+        return Err(PinoqError::PasswordMismatch);
+    }
+    Ok(password)
+}
+
+/// reads a line without its trailing newline
+fn read_line<R>(mut r: R) -> Result<Secret>
+where
+    R: BufRead,
+{
+    // large enough not to be reallocated, which would leave a copy behind
+    let mut line = String::with_capacity(1024);
+    if r.read_line(&mut line)? == 0 {
+        return Err(std::io::Error::from(ErrorKind::UnexpectedEof).into());
+    }
+    if line.ends_with('\n') {
+        line.pop();
+        if line.ends_with('\r') {
+            line.pop();
+        }
+    }
+    Ok(line.into())
+}
+
+/// disables the echo of the terminal until dropped
+struct EchoOff {
+    termios: libc::termios,
+}
+
+impl EchoOff {
+    fn new() -> Result<Self> {
+        let mut termios = unsafe { std::mem::zeroed::<libc::termios>() };
+        if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut termios) } != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+
+        let mut silent = termios;
+        silent.c_lflag &= !libc::ECHO;
+        if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &silent) } != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        Ok(Self { termios })
+    }
+}
+
+impl Drop for EchoOff {
+    fn drop(&mut self) {
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &self.termios) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_line() {
+        let mut input = &b"first\nsecond\r\nthird"[..];
+        assert_eq!(&read_line(&mut input).unwrap()[..], b"first");
+        assert_eq!(&read_line(&mut input).unwrap()[..], b"second");
+        assert_eq!(&read_line(&mut input).unwrap()[..], b"third");
+        assert!(matches!(read_line(&mut input), Err(PinoqError::IO(_))));
+    }
+}
@@ -0,0 +1,53 @@
+use crate::pinoq::config::{Config, Current};
+use crate::pinoq::{mkfs, Allocation, Kdf, MkfsOptions, Size};
+
+use tempfile::{tempdir, TempDir};
+
+/// a cheap KDF and no random fill, allocations are predictable
+pub fn test_options() -> MkfsOptions {
+    MkfsOptions {
+        kdf: Kdf::pbkdf2(1000),
+        fast: true,
+        headerless: false,
+        allocation: Allocation::FirstFit,
+    }
+}
+
+/// a volume with one aspect for each of the `passwords`, removed along with the directory
+pub fn test_volume(passwords: &[&str], blocks: u32) -> (TempDir, String) {
+    test_volume_with(passwords, Size::Blocks(blocks), &test_options())
+}
+
+pub fn test_volume_with(
+    passwords: &[&str],
+    size: Size,
+    options: &MkfsOptions,
+) -> (TempDir, String) {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("my-volume.pnoq");
+    let path = path.to_str().unwrap().to_string();
+    mkfs(passwords, size, &path, options).unwrap();
+    (dir, path)
+}
+
+pub fn test_current(aspect: u32, password: &str) -> Current {
+    Current {
+        aspect,
+        password: Some(password.to_string()),
+        password_from: None,
+        keyfile: None,
+    }
+}
+
+pub fn test_config(path: &str, aspect: u32, password: &str) -> Config {
+    Config {
+        disk: path.to_string(),
+        mount: "".to_string(),
+        current: test_current(aspect, password),
+        hide_timestamps: false,
+        headerless: false,
+        kdf: Kdf::default(),
+        protect: vec![],
+        journal: None,
+    }
+}
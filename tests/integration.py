@@ -29,7 +29,9 @@ class Integration(unittest.TestCase):
         self.directory = '/tmp/pinoq/'
         self.config_path = '/tmp/pinoq.toml'
         self.disk = '/tmp/volume.pnoq'
-        subprocess.run([PINOQ_BIN, '--mkfs', '2', '1024', self.disk, 'password'])
+        # the passwords of the aspects are read from the pipe, one per line
+        subprocess.run([PINOQ_BIN, 'mkfs', '--aspects', '2', '--blocks', '1024', self.disk],
+                       input='password\npassword\n', text=True)
 
     def test_pinoq_mount_sanity(self):
         config = Config(self.disk, self.directory, 1, 'password')
@@ -52,7 +54,8 @@ class Integration(unittest.TestCase):
         files = os.listdir(self.directory)
         self.assertIn('first.txt', files, 'File does not exist')
 
-        # trying the first aspect
+        # trying the first aspect, it shares the password of the second one
+        # so the blocks of the second one are kept
         config = Config(self.disk, self.directory, 0, 'password')
         with open(self.config_path, 'w') as file:
             file.write(str(config))
@@ -105,7 +108,7 @@ class Integration(unittest.TestCase):
         if self.pid:
             os.kill(self.pid, signal.SIGTERM)
             time.sleep(2)
-        process = subprocess.Popen([PINOQ_BIN, '--mount', self.config_path],
+        process = subprocess.Popen([PINOQ_BIN, 'mount', self.config_path],
                                    stdout=subprocess.DEVNULL, stderr=subprocess.DEVNULL)
         self.pid = process.pid
         time.sleep(2)